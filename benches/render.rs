@@ -0,0 +1,97 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use hogan::config::Environment;
+use hogan::template::{CompiledTemplate, Metadata};
+use serde_json::json;
+use std::io::sink;
+
+const TEMPLATE: &str = r#"<configuration>
+  <appSettings>
+    <add key="Environment" value="{{Environment}}" />
+    <add key="Region" value="{{Region.Key}}" />
+    <add key="Memcache" value="{{#comma-list Memcache.Servers}}{{Endpoint}}:{{Port}}{{/comma-list}}" />
+    {{#each Services}}
+    <add key="{{@key}}" value="{{url-add-slash endpoint}}" />
+    {{/each}}
+    {{#if Feature.Enabled}}<add key="Feature" value="{{yaml-string Feature.Name}}" />{{/if}}
+  </appSettings>
+</configuration>
+"#;
+
+fn environments(count: usize) -> Vec<Environment> {
+    (0..count)
+        .map(|i| Environment {
+            environment: format!("ENV{}", i),
+            environment_type: None,
+            config_data: json!({
+                "Environment": format!("ENV{}", i),
+                "Region": { "Key": "TEST" },
+                "Memcache": {
+                    "Servers": [
+                        { "Endpoint": "192.168.1.100", "Port": "1122" },
+                        { "Endpoint": "192.168.1.101", "Port": "1122" }
+                    ]
+                },
+                "Services": {
+                    "Auth": { "endpoint": "https://auth.example.com" },
+                    "Search": { "endpoint": "https://search.example.com/" }
+                },
+                "Feature": { "Enabled": true, "Name": "C:\\Feature" }
+            }),
+        })
+        .collect()
+}
+
+// Both cases render through `CompiledTemplate` in the same way, and only differ in whether the
+// template is parsed once or for every environment
+fn render(c: &mut Criterion) {
+    let handlebars = hogan::transform::handlebars(true);
+    let template = CompiledTemplate::compile(TEMPLATE).unwrap();
+
+    let mut group = c.benchmark_group("render");
+    for count in [10, 100] {
+        let environments = environments(count);
+
+        group.bench_with_input(
+            BenchmarkId::new("reparse", count),
+            &environments,
+            |b, environments| {
+                b.iter(|| {
+                    for environment in environments {
+                        CompiledTemplate::compile(TEMPLATE)
+                            .unwrap()
+                            .render(
+                                &handlebars,
+                                &environment.config_data,
+                                &Metadata::new(environment, None),
+                                sink(),
+                            )
+                            .unwrap();
+                    }
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("compiled", count),
+            &environments,
+            |b, environments| {
+                b.iter(|| {
+                    for environment in environments {
+                        template
+                            .render(
+                                &handlebars,
+                                &environment.config_data,
+                                &Metadata::new(environment, None),
+                                sink(),
+                            )
+                            .unwrap();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
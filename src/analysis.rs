@@ -0,0 +1,488 @@
+use crate::config::Environment;
+use crate::template::Template;
+use anyhow::{Context, Result};
+use handlebars::template::{
+    BlockParam, HelperTemplate, Parameter, Template as HbTemplate, TemplateElement,
+};
+use handlebars::Path as HbPath;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::PathBuf;
+
+// Helpers which never resolve to a config value when used without parameters
+const KNOWN_HELPERS: &[&str] = &[
+    "if",
+    "unless",
+    "each",
+    "with",
+    "lookup",
+    "raw",
+    "log",
+    "eq",
+    "ne",
+    "gt",
+    "gte",
+    "lt",
+    "lte",
+    "and",
+    "or",
+    "not",
+    "len",
+    "comma-list",
+    "equal",
+    "lowercase",
+    "url-add-slash",
+    "url-rm-path",
+    "url-rm-slash",
+    "yaml-string",
+    "hogan-file",
+];
+
+// Block helpers which render their body once per item of their first parameter
+const ITERATING_HELPERS: &[&str] = &["each", "comma-list"];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Segment {
+    Key(String),
+    Item,
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Segment::Key(key) => write!(f, "{}", key),
+            Segment::Item => write!(f, "[]"),
+        }
+    }
+}
+
+/// How a key is used by a template
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum KeyUsage {
+    /// Rendered directly, or iterated over. A missing value is an error in strict mode
+    Value,
+    /// Passed as a parameter to a helper such as `if`, `eq` or `or`
+    Param,
+}
+
+/// A config path referenced by a template. Items of arrays are represented by `[]`,
+/// for example `Memcache.Servers.[].Endpoint`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyReference {
+    segments: Vec<Segment>,
+    pub usage: KeyUsage,
+}
+
+impl KeyReference {
+    pub fn path(&self) -> String {
+        self.segments
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>()
+            .join(".")
+    }
+
+    /// Checks if the key resolves against the config data. Keys below an array must exist on
+    /// every item of that array
+    pub fn exists_in(&self, config_data: &Value) -> bool {
+        fn resolve(value: &Value, segments: &[Segment]) -> bool {
+            match segments.split_first() {
+                None => !value.is_null(),
+                Some((Segment::Key(key), rest)) => {
+                    let child = match value {
+                        Value::Object(map) => map.get(key),
+                        Value::Array(list) => key.parse::<usize>().ok().and_then(|i| list.get(i)),
+                        _ => None,
+                    };
+                    child.is_some_and(|c| resolve(c, rest))
+                }
+                Some((Segment::Item, rest)) => match value {
+                    Value::Array(list) => list.iter().all(|item| resolve(item, rest)),
+                    Value::Object(map) => map.values().all(|item| resolve(item, rest)),
+                    _ => false,
+                },
+            }
+        }
+
+        resolve(config_data, &self.segments)
+    }
+}
+
+impl fmt::Display for KeyReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path())
+    }
+}
+
+// Splits a raw handlebars path such as `../Foo.[Bar Baz]/Qux` into whether it starts at the
+// root, how many scopes it walks up and its named segments
+fn split_raw_path(raw: &str) -> (bool, usize, Vec<String>) {
+    let mut rest = raw;
+    let mut root = false;
+    let mut up = 0;
+
+    if let Some(r) = rest.strip_prefix("@root") {
+        root = true;
+        rest = r.trim_start_matches(['.', '/']);
+    }
+    loop {
+        if let Some(r) = rest.strip_prefix("../") {
+            up += 1;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("./") {
+            rest = r;
+        } else if rest == ".." {
+            up += 1;
+            rest = "";
+        } else {
+            break;
+        }
+    }
+
+    let mut names = Vec::new();
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        let name = match c {
+            '.' | '/' => continue,
+            '[' => chars.by_ref().take_while(|c| *c != ']').collect::<String>(),
+            c => {
+                let mut name = c.to_string();
+                while let Some(next) = chars.next_if(|c| *c != '.' && *c != '/') {
+                    name.push(next);
+                }
+                name
+            }
+        };
+        if name != "this" {
+            names.push(name);
+        }
+    }
+
+    (root, up, names)
+}
+
+#[derive(Default)]
+struct Scope {
+    prefix: Vec<Segment>,
+    block_params: HashMap<String, Vec<Segment>>,
+}
+
+#[derive(Default)]
+struct KeyCollector {
+    scopes: Vec<Scope>,
+    keys: BTreeSet<KeyReference>,
+}
+
+impl KeyCollector {
+    fn resolve(&self, path: &HbPath) -> Option<Vec<Segment>> {
+        let raw = match path {
+            HbPath::Relative((_, raw)) => raw,
+            // @index, @key and other local variables are not config values
+            HbPath::Local(_) => return None,
+        };
+        let (root, up, names) = split_raw_path(raw);
+
+        let mut resolved = if root {
+            Vec::new()
+        } else {
+            let depth = self.scopes.len().saturating_sub(up + 1);
+            self.scopes
+                .get(depth)
+                .map(|s| s.prefix.clone())
+                .unwrap_or_default()
+        };
+
+        let mut names = names.into_iter().peekable();
+        if let Some(first) = names.peek() {
+            if let Some(prefix) = self
+                .scopes
+                .iter()
+                .rev()
+                .find_map(|s| s.block_params.get(first))
+            {
+                resolved = prefix.clone();
+                names.next();
+            }
+        }
+
+        resolved.extend(names.map(Segment::Key));
+        if resolved.is_empty() {
+            None
+        } else {
+            Some(resolved)
+        }
+    }
+
+    fn add(&mut self, path: &HbPath, usage: KeyUsage) -> Option<Vec<Segment>> {
+        let segments = self.resolve(path)?;
+        self.keys.insert(KeyReference {
+            segments: segments.clone(),
+            usage,
+        });
+        Some(segments)
+    }
+
+    fn visit_param(&mut self, param: &Parameter, usage: KeyUsage) -> Option<Vec<Segment>> {
+        match param {
+            Parameter::Path(path) => self.add(path, usage),
+            Parameter::Subexpression(subexpression) => {
+                if let TemplateElement::Expression(ref helper) = *subexpression.element {
+                    self.visit_helper_params(helper, KeyUsage::Param);
+                }
+                None
+            }
+            Parameter::Name(_) | Parameter::Literal(_) => None,
+        }
+    }
+
+    fn visit_helper_params(&mut self, helper: &HelperTemplate, usage: KeyUsage) {
+        for param in helper.params.iter() {
+            self.visit_param(param, usage);
+        }
+        for param in helper.hash.values() {
+            self.visit_param(param, usage);
+        }
+    }
+
+    fn visit_expression(&mut self, helper: &HelperTemplate) {
+        let name_only = helper.params.is_empty() && helper.hash.is_empty();
+        match &helper.name {
+            Parameter::Name(name) if name_only && !KNOWN_HELPERS.contains(&name.as_str()) => {
+                if let Ok(path) = HbPath::parse(name) {
+                    self.add(&path, KeyUsage::Value);
+                }
+            }
+            Parameter::Path(path) if name_only => {
+                self.add(path, KeyUsage::Value);
+            }
+            name => {
+                if let Parameter::Subexpression(_) = name {
+                    self.visit_param(name, KeyUsage::Value);
+                }
+                self.visit_helper_params(helper, KeyUsage::Param)
+            }
+        }
+    }
+
+    fn visit_block(&mut self, helper: &HelperTemplate) {
+        let name = match &helper.name {
+            Parameter::Name(name) => name.as_str(),
+            _ => "",
+        };
+        let iterating = ITERATING_HELPERS.contains(&name);
+        let scoped = iterating || name == "with";
+
+        let mut params = helper.params.iter();
+        let context = if scoped {
+            let usage = if name == "comma-list" {
+                KeyUsage::Param
+            } else {
+                KeyUsage::Value
+            };
+            params.next().and_then(|p| self.visit_param(p, usage))
+        } else {
+            None
+        };
+        for param in params.chain(helper.hash.values()) {
+            self.visit_param(param, KeyUsage::Param);
+        }
+
+        // The path of an emitted file is itself a template, rendered in the current scope
+        if name == "hogan-file" {
+            if let Some(Parameter::Literal(Value::String(path))) = helper.params.first() {
+                if let Ok(path) = HbTemplate::compile(path) {
+                    self.visit_template(&path);
+                }
+            }
+        }
+
+        if let Some(inverse) = &helper.inverse {
+            self.visit_template(inverse);
+        }
+
+        if let Some(template) = &helper.template {
+            match context {
+                Some(mut prefix) => {
+                    if iterating {
+                        prefix.push(Segment::Item);
+                    }
+                    let mut block_params = HashMap::new();
+                    match &helper.block_param {
+                        Some(BlockParam::Single(Parameter::Name(param)))
+                        | Some(BlockParam::Pair((Parameter::Name(param), _))) => {
+                            block_params.insert(param.to_owned(), prefix.clone());
+                        }
+                        _ => {}
+                    }
+                    self.scopes.push(Scope {
+                        prefix,
+                        block_params,
+                    });
+                    self.visit_template(template);
+                    self.scopes.pop();
+                }
+                None => self.visit_template(template),
+            }
+        }
+    }
+
+    fn visit_template(&mut self, template: &HbTemplate) {
+        for element in template.elements.iter() {
+            match element {
+                TemplateElement::Expression(helper) | TemplateElement::HtmlExpression(helper) => {
+                    self.visit_expression(helper)
+                }
+                TemplateElement::HelperBlock(helper) => self.visit_block(helper),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Statically walks a template and lists every config path it references, including paths
+/// used within helpers and blocks
+pub fn template_keys(source: &str) -> Result<Vec<KeyReference>> {
+    let template = HbTemplate::compile(source).with_context(|| "Error parsing template")?;
+    let mut collector = KeyCollector {
+        scopes: vec![Scope::default()],
+        ..Default::default()
+    };
+    collector.visit_template(&template);
+
+    // A key which is rendered is also reported as a param when used both ways
+    let keys = collector.keys;
+    Ok(keys
+        .iter()
+        .filter(|k| {
+            k.usage == KeyUsage::Value
+                || !keys.contains(&KeyReference {
+                    segments: k.segments.clone(),
+                    usage: KeyUsage::Value,
+                })
+        })
+        .cloned()
+        .collect())
+}
+
+/// The keys referenced by a template that are missing from a single environment
+#[derive(Debug)]
+pub struct EnvironmentCoverage {
+    pub environment: String,
+    pub missing: Vec<KeyReference>,
+}
+
+/// The keys referenced by a template and which environments are missing them
+#[derive(Debug)]
+pub struct TemplateCoverage {
+    pub template: PathBuf,
+    pub keys: Vec<KeyReference>,
+    pub environments: Vec<EnvironmentCoverage>,
+}
+
+impl TemplateCoverage {
+    pub fn missing_count(&self) -> usize {
+        self.environments.iter().map(|e| e.missing.len()).sum()
+    }
+}
+
+/// Cross checks the keys referenced by each template against every environment
+pub fn coverage(
+    templates: &[Template],
+    environments: &[Environment],
+) -> Result<Vec<TemplateCoverage>> {
+    templates
+        .iter()
+        .map(|template| {
+            let keys = template_keys(&template.contents)
+                .with_context(|| format!("Analyzing template {:?}", template.path))?;
+            let environments = environments
+                .iter()
+                .map(|environment| EnvironmentCoverage {
+                    environment: environment.environment.clone(),
+                    missing: keys
+                        .iter()
+                        .filter(|k| !k.exists_in(&environment.config_data))
+                        .cloned()
+                        .collect(),
+                })
+                .collect();
+
+            Ok(TemplateCoverage {
+                template: template.path.clone(),
+                keys,
+                environments,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(source: &str) -> Vec<String> {
+        template_keys(source)
+            .unwrap()
+            .iter()
+            .map(|k| k.path())
+            .collect()
+    }
+
+    #[test]
+    fn test_template_keys() {
+        assert_eq!(
+            paths("{{Region.Key}} {{{DB.Endpoint}}} {{yaml-string App.Path}}"),
+            vec!["App.Path", "DB.Endpoint", "Region.Key"]
+        );
+
+        assert_eq!(
+            paths(
+                r#"{{#or (eq Region.Key "TEST") (eq Region.Key "TEST2")}}{{Foo}}{{else}}{{Bar}}{{/or}}"#
+            ),
+            vec!["Bar", "Foo", "Region.Key"]
+        );
+
+        assert_eq!(
+            paths("{{#comma-list Memcache.Servers}}{{Endpoint}}:{{../Port}}{{/comma-list}}"),
+            vec!["Memcache.Servers", "Memcache.Servers.[].Endpoint", "Port"]
+        );
+
+        assert_eq!(
+            paths("{{#each Services as |service|}}{{service.Name}}{{@index}}{{/each}}"),
+            vec!["Services", "Services.[].Name"]
+        );
+
+        assert_eq!(
+            paths("{{#with DB}}{{Endpoint}}{{@root.Region.Key}}{{/with}}"),
+            vec!["DB", "DB.Endpoint", "Region.Key"]
+        );
+
+        assert_eq!(
+            paths(
+                r#"{{#each Services}}{{#hogan-file "conf.d/{{Name}}.conf"}}{{Port}}{{/hogan-file}}{{/each}}"#
+            ),
+            vec!["Services", "Services.[].Name", "Services.[].Port"]
+        );
+    }
+
+    #[test]
+    fn test_key_exists() {
+        let config: Value = serde_json::from_str(
+            r#"{"Region": {"Key": "TEST"}, "Servers": [{"Port": 1}, {"Port": 2}, {"Host": "a"}]}"#,
+        )
+        .unwrap();
+        let keys = template_keys(
+            "{{Region.Key}}{{Region.Name}}{{#each Servers}}{{Port}}{{/each}}{{Servers.[0].Port}}",
+        )
+        .unwrap();
+
+        let missing = keys
+            .iter()
+            .filter(|k| !k.exists_in(&config))
+            .map(|k| k.path())
+            .collect::<Vec<String>>();
+
+        assert_eq!(missing, vec!["Region.Name", "Servers.[].Port"]);
+    }
+}
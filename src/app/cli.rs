@@ -1,85 +1,351 @@
-use crate::app::config::App;
-use crate::app::config::AppCommon;
+use crate::app::config::{App, AppCommon, AppMirror};
+use crate::app::lock::lock_configs;
 use anyhow::{Context, Result};
-use hogan::config::ConfigDir;
+use handlebars::{Handlebars, RenderError};
+use hogan::config::{ConfigDir, Environment};
 use hogan::error::HoganError;
-use hogan::template::TemplateDir;
+use hogan::manifest::{Manifest, ManifestEntry};
+use hogan::template::{OutputPaths, Rendered, Template, TemplateDir};
 use regex::Regex;
-use std::fs::File;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::OpenOptions;
+use std::fs::{self, File};
 use std::io::ErrorKind::AlreadyExists;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
 
+#[allow(clippy::too_many_arguments)]
 pub fn cli(
     templates_path: PathBuf,
     environments_regex: Regex,
     templates_regex: Regex,
     common: AppCommon,
+    mirror: AppMirror,
+    gitignore: bool,
     ignore_existing: bool,
+    prune: bool,
+    dry_run: bool,
+    all_errors: bool,
+    jobs: Option<usize>,
+    update: bool,
 ) -> Result<()> {
     let handlebars = hogan::transform::handlebars(common.strict);
 
-    let template_dir = TemplateDir::new(templates_path)?;
-    let mut templates = template_dir.find(templates_regex);
+    // Every run records what it writes, so that a later --prune knows what earlier runs wrote
+    let mut manifest = Manifest::load(&templates_path)?;
+    let mut rendered_outputs = HashSet::new();
+
+    let template_dir = TemplateDir::new(templates_path.clone(), gitignore)?;
+    let templates = template_dir.find(templates_regex);
     println!("Loaded {} template file(s)", templates.len());
 
-    let config_dir = ConfigDir::new(
-        common.configs_url,
-        &common.ssh_key,
+    let configs_url = common.configs_url()?;
+    let mut config_dir = ConfigDir::new(
+        configs_url.clone(),
+        &common.git_auth()?,
         common.native_git,
         common.native_fetch,
         common.native_clone,
+        common.git_runner(),
+        common.clone_options(),
+        mirror.mirror().as_ref(),
     )?;
+    config_dir.set_gitignore(gitignore);
+    lock_configs(&mut config_dir, &configs_url, &templates_path, update)?;
+    if let Some(signers) = common.allowed_signers() {
+        let sha = config_dir.head_sha().unwrap_or_default().to_owned();
+        config_dir.verify_commit(&sha, &signers)?;
+        println!("Verified the signature of configs at {}", sha);
+    }
     let environments = config_dir.find(App::config_regex(&environments_regex)?);
     println!("Loaded {} config file(s)", environments.len());
 
-    for environment in environments {
-        println!("Updating templates for {}", environment.environment);
-
-        for template in &mut templates {
+    // When collecting all errors nothing is written until every template has rendered
+    let mut pending = Vec::new();
+    let mut failures = Vec::new();
+
+    let pairs = environments
+        .iter()
+        .flat_map(|environment| {
+            templates
+                .iter()
+                .map(move |template| (environment, template))
+        })
+        .collect::<Vec<(&Environment, &Template)>>();
+    let jobs = jobs
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
+    debug!("Rendering {} file(s) with {} job(s)", pairs.len(), jobs);
+
+    let mut current_environment = None;
+    let mut outputs = OutputPaths::default();
+    render_in_order(
+        &handlebars,
+        config_dir.head_sha(),
+        &pairs,
+        jobs,
+        |(environment, template), result| {
+            if current_environment != Some(&environment.environment) {
+                println!("Updating templates for {}", environment.environment);
+                current_environment = Some(&environment.environment);
+            }
             debug!("Transforming {:?}", template.path);
 
-            let rendered = match template.render(&handlebars, &environment) {
+            let rendered = match result {
                 Ok(rendered) => rendered,
+                Err(err) if all_errors => {
+                    failures.push(RenderFailure::new(template, environment, &err));
+                    return Ok(());
+                }
                 Err(err) => {
                     error!(
-                        "There was an error rendering environment {} with template {:?}. Error: {:?}",
+                        "There was an error rendering environment {} with template {:?}. \
+                         Error: {:?}",
                         environment.environment, template.path, err
                     );
                     return Err(err);
                 }
             };
-            trace!("Rendered: {:?}", rendered.contents);
-
-            if ignore_existing {
-                if let Err(e) = match OpenOptions::new()
-                    .write(true)
-                    .create_new(true)
-                    .open(&rendered.path)
-                {
-                    Ok(ref mut f) => f.write_all(&rendered.contents),
-                    Err(ref e) if e.kind() == AlreadyExists => {
-                        println!("Skipping {:?} - config already exists.", rendered.path);
-                        trace!("Skipping {:?} - config already exists.", rendered.path);
-                        Ok(())
+            for rendered in &rendered {
+                outputs.claim(&rendered.path, &environment.environment)?;
+            }
+            if all_errors {
+                pending.push((template, environment, rendered));
+                return Ok(());
+            }
+            for rendered in rendered {
+                trace!("Rendered: {:?}", rendered.contents);
+                let written = write_rendered(&rendered, ignore_existing)?;
+                record_output(
+                    &mut manifest,
+                    &mut rendered_outputs,
+                    &templates_path,
+                    template,
+                    environment,
+                    &rendered,
+                    written,
+                );
+            }
+            Ok(())
+        },
+    )?;
+
+    if !failures.is_empty() {
+        print_failures(&failures);
+        return Err(HoganError::UnknownError {
+            msg: format!(
+                "{} template and environment pair(s) failed to render",
+                failures.len()
+            ),
+        })
+        .with_context(|| "No files were written");
+    }
+
+    for (template, environment, rendered) in pending
+        .into_iter()
+        .flat_map(|(t, e, rendered)| rendered.into_iter().map(move |r| (t, e, r)))
+    {
+        let written = write_rendered(&rendered, ignore_existing)?;
+        record_output(
+            &mut manifest,
+            &mut rendered_outputs,
+            &templates_path,
+            template,
+            environment,
+            &rendered,
+            written,
+        );
+    }
+
+    if prune {
+        // Stale outputs are judged against every environment, not just the filtered ones
+        let known_environments = config_dir
+            .find(App::config_regex(&App::parse_regex(".+")?)?)
+            .into_iter()
+            .map(|e| e.environment)
+            .collect::<HashSet<String>>();
+        let stale = manifest.stale(&templates_path, &known_environments, &rendered_outputs);
+
+        if dry_run {
+            for entry in &stale {
+                println!("Would remove stale output {:?}", entry.output);
+            }
+            println!("Dry run: {} stale output(s) found", stale.len());
+        } else {
+            for entry in &stale {
+                println!("Removing stale output {:?}", entry.output);
+            }
+            manifest.prune(&templates_path, &stale)?;
+            println!("Pruned {} stale output(s)", stale.len());
+        }
+    }
+    manifest.save(&templates_path)?;
+
+    Ok(())
+}
+
+type RenderPair<'a> = (&'a Environment, &'a Template);
+
+// Renders the pairs on a pool of worker threads, handing the results to `consume` in the same
+// order as the pairs. Rendering stops as soon as `consume` returns an error
+fn render_in_order<'a, F>(
+    handlebars: &Handlebars,
+    sha: Option<&str>,
+    pairs: &[RenderPair<'a>],
+    jobs: usize,
+    mut consume: F,
+) -> Result<()>
+where
+    F: FnMut(RenderPair<'a>, Result<Vec<Rendered>>) -> Result<()>,
+{
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..jobs.max(1).min(pairs.len()) {
+            let sender = sender.clone();
+            let (next, stop) = (&next, &stop);
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let (environment, template) = match pairs.get(index) {
+                        Some(pair) => *pair,
+                        None => break,
+                    };
+                    let result = template.render(handlebars, environment, sha);
+                    if sender.send((index, result)).is_err() {
+                        break;
                     }
-                    Err(e) => Err(e),
-                } {
-                    return Err(HoganError::UnknownError {
-                        msg: format!("Error transforming {:?} due to {:?}", rendered.path, e),
-                    })
-                    .with_context(|| "Error while ignoring existing");
                 }
-            } else {
-                File::create(&rendered.path)?
-                    .write_all(&rendered.contents)
-                    .with_context(|| format!("Error transforming {:?}", rendered.path))?;
+            });
+        }
+        drop(sender);
+
+        let mut completed = BTreeMap::new();
+        let mut expected = 0;
+        for (index, result) in receiver {
+            completed.insert(index, result);
+            while let Some(result) = completed.remove(&expected) {
+                if let Err(e) = consume(pairs[expected], result) {
+                    stop.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+                expected += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+// Returns false if the file was skipped because it already exists
+fn write_rendered(rendered: &Rendered, ignore_existing: bool) -> Result<bool> {
+    // Files emitted with hogan-file may be nested in directories that do not exist yet
+    if let Some(parent) = rendered.path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Error creating the directory {:?}", parent))?;
+    }
+    if ignore_existing {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&rendered.path)
+        {
+            Ok(ref mut f) => f.write_all(&rendered.contents).map(|_| true),
+            Err(ref e) if e.kind() == AlreadyExists => {
+                println!("Skipping {:?} - config already exists.", rendered.path);
+                trace!("Skipping {:?} - config already exists.", rendered.path);
+                Ok(false)
             }
+            Err(e) => Err(e),
         }
+        .map_err(|e| HoganError::UnknownError {
+            msg: format!("Error transforming {:?} due to {:?}", rendered.path, e),
+        })
+        .with_context(|| "Error while ignoring existing")
+    } else {
+        File::create(&rendered.path)?
+            .write_all(&rendered.contents)
+            .with_context(|| format!("Error transforming {:?}", rendered.path))?;
+        Ok(true)
     }
+}
 
-    Ok(())
+fn record_output(
+    manifest: &mut Manifest,
+    rendered_outputs: &mut HashSet<PathBuf>,
+    templates_path: &Path,
+    template: &Template,
+    environment: &Environment,
+    rendered: &Rendered,
+    written: bool,
+) {
+    let output = relative_path(templates_path, &rendered.path);
+    if written {
+        manifest.insert(ManifestEntry {
+            template: relative_path(templates_path, &template.path),
+            environment: environment.environment.clone(),
+            output: output.clone(),
+        });
+    }
+    rendered_outputs.insert(output);
+}
+
+fn relative_path(base: &Path, path: &Path) -> PathBuf {
+    path.strip_prefix(base).unwrap_or(path).to_path_buf()
+}
+
+struct RenderFailure {
+    template: PathBuf,
+    environment: String,
+    line: Option<usize>,
+    column: Option<usize>,
+    message: String,
+}
+
+impl RenderFailure {
+    fn new(template: &Template, environment: &Environment, err: &anyhow::Error) -> Self {
+        let render_error = err.downcast_ref::<RenderError>();
+        RenderFailure {
+            template: template.path.clone(),
+            environment: environment.environment.clone(),
+            line: render_error.and_then(|e| e.line_no),
+            column: render_error.and_then(|e| e.column_no),
+            message: render_error
+                .map(|e| e.desc.clone())
+                .unwrap_or_else(|| format!("{:#}", err)),
+        }
+    }
+}
+
+fn print_failures(failures: &[RenderFailure]) {
+    let mut by_template: BTreeMap<&Path, Vec<&RenderFailure>> = BTreeMap::new();
+    for failure in failures {
+        by_template
+            .entry(failure.template.as_path())
+            .or_default()
+            .push(failure);
+    }
+
+    println!(
+        "Found {} render error(s) in {} template(s)",
+        failures.len(),
+        by_template.len()
+    );
+    for (template, failures) in by_template {
+        println!("{:?}", template);
+        for failure in failures {
+            let location = match (failure.line, failure.column) {
+                (Some(line), Some(column)) => format!(" (line {}, column {})", line, column),
+                (Some(line), None) => format!(" (line {})", line),
+                _ => String::new(),
+            };
+            println!("  {}{}: {}", failure.environment, location, failure.message);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -233,4 +499,260 @@ mod tests {
         )
         .unwrap());
     }
+
+    #[cfg(not(all(target_env = "msvc", target_arch = "x86_64")))]
+    #[test]
+    fn test_emitted_files_per_environment() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let template_path = temp_dir.path().join("vhost.template.config");
+        let transform = || {
+            let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+            cmd.args([
+                "transform",
+                "--configs",
+                "tests/fixtures/configs",
+                "--templates",
+                temp_dir.path().to_str().unwrap(),
+            ]);
+            cmd
+        };
+
+        // Every environment would write the same file
+        std::fs::write(
+            &template_path,
+            r#"{{#hogan-file "conf.d/app.conf"}}{{@hogan.environment}}{{/hogan-file}}"#,
+        )
+        .unwrap();
+        transform()
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("was already rendered").from_utf8());
+        // The first environment's file is not replaced by the others
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join("conf.d/app.conf")).unwrap(),
+            "TEST"
+        );
+
+        std::fs::write(
+            &template_path,
+            r#"{{#hogan-file "conf.d/{{@hogan.environment}}.conf"}}{{@hogan.environment}}{{/hogan-file}}"#,
+        )
+        .unwrap();
+        transform().assert().success();
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join("conf.d/TEST.conf")).unwrap(),
+            "TEST"
+        );
+    }
+
+    #[cfg(not(all(target_env = "msvc", target_arch = "x86_64")))]
+    #[test]
+    fn test_prune() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        fs_extra::copy_items(
+            &[
+                "tests/fixtures/projects/templates",
+                "tests/fixtures/configs",
+            ],
+            temp_dir.path(),
+            &dir::CopyOptions::new(),
+        )
+        .unwrap();
+
+        let templates_path = temp_dir.path().join("templates");
+        let configs_path = temp_dir.path().join("configs");
+        let transform_args = [
+            "transform",
+            "--configs",
+            configs_path.to_str().unwrap(),
+            "--templates",
+            templates_path.to_str().unwrap(),
+            "--prune",
+        ];
+
+        // Runs without --prune record what they write too
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        cmd.args(&transform_args[..5]).assert().success();
+        assert!(templates_path.join(".hogan-manifest.json").is_file());
+
+        // Remove an environment, its outputs are now stale
+        std::fs::remove_file(configs_path.join("config.TEST2.json")).unwrap();
+        let stale_path = templates_path.join("project-1/Web.TEST2.config");
+        assert!(stale_path.is_file());
+
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        cmd.args(transform_args)
+            .arg("--dry-run")
+            .assert()
+            .success()
+            .stdout(
+                predicate::str::contains("Would remove stale output")
+                    .and(predicate::str::contains("Web.TEST2.config"))
+                    .from_utf8(),
+            );
+        assert!(stale_path.is_file());
+
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        cmd.args(transform_args)
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("Pruned 6 stale output(s)").from_utf8());
+        assert!(!stale_path.exists());
+        assert!(templates_path.join("project-1/Web.TEST.config").is_file());
+    }
+
+    #[cfg(not(all(target_env = "msvc", target_arch = "x86_64")))]
+    #[test]
+    fn test_all_errors() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        fs_extra::copy_items(
+            &["tests/fixtures/projects/templates"],
+            temp_dir.path(),
+            &dir::CopyOptions::new(),
+        )
+        .unwrap();
+
+        let templates_path = temp_dir.path().join("templates");
+        std::fs::create_dir(templates_path.join("project-5")).unwrap();
+        std::fs::write(
+            templates_path.join("project-5/Broken.template.yaml"),
+            "a: 1\nb: {{Missing.Key}}\n",
+        )
+        .unwrap();
+
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        let cmd = cmd.args([
+            "transform",
+            "--configs",
+            "tests/fixtures/configs",
+            "--templates",
+            templates_path.to_str().unwrap(),
+            "--strict",
+            "--all-errors",
+        ]);
+
+        cmd.assert().failure().stdout(
+            predicate::str::contains("Found 8 render error(s) in 3 template(s)")
+                .and(predicate::str::contains(
+                    r#"EMPTY (line 2, column 4): Variable "Missing.Key" not found in strict mode."#,
+                ))
+                .from_utf8(),
+        );
+
+        // Nothing is written when any template fails
+        assert!(!templates_path.join("project-1/Web.TEST.config").exists());
+        assert!(!templates_path.join("project-5/Broken.TEST.yaml").exists());
+    }
+
+    #[cfg(not(all(target_env = "msvc", target_arch = "x86_64")))]
+    #[test]
+    fn test_transform_jobs() {
+        let mut outputs = Vec::new();
+        for jobs in ["1", "3"].iter() {
+            let temp_dir = tempfile::tempdir().unwrap();
+
+            fs_extra::copy_items(
+                &["tests/fixtures/projects/templates"],
+                temp_dir.path(),
+                &dir::CopyOptions::new(),
+            )
+            .unwrap();
+
+            let templates_path = temp_dir.path().join("templates");
+
+            let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+            let cmd = cmd.args([
+                "transform",
+                "--configs",
+                "tests/fixtures/configs",
+                "--templates",
+                templates_path.to_str().unwrap(),
+                "--jobs",
+                jobs,
+            ]);
+
+            let output = cmd.output().unwrap();
+            assert!(output.status.success());
+            // Paths differ per run, so compare everything after the templates are found
+            let stdout = String::from_utf8(output.stdout).unwrap();
+            outputs.push(stdout[stdout.find("Loaded").unwrap()..].to_string());
+
+            assert!(!dir_diff::is_different(
+                templates_path.join("project-1"),
+                Path::new("tests/fixtures/projects/rendered/project-1")
+            )
+            .unwrap());
+
+            assert!(!dir_diff::is_different(
+                templates_path.join("project-2"),
+                Path::new("tests/fixtures/projects/rendered/project-2")
+            )
+            .unwrap());
+        }
+
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[cfg(not(all(target_env = "msvc", target_arch = "x86_64")))]
+    #[test]
+    fn test_transform_project() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        fs_extra::copy_items(
+            &["tests/fixtures/projects/templates"],
+            temp_dir.path(),
+            &dir::CopyOptions::new(),
+        )
+        .unwrap();
+
+        let templates_path = temp_dir.path().join("templates");
+        std::fs::write(
+            templates_path.join(".hogan.toml"),
+            format!(
+                "configs = {:?}\nenvironments-filter = \"TEST2\"\n",
+                Path::new("tests/fixtures/configs")
+                    .canonicalize()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+            ),
+        )
+        .unwrap();
+
+        // The project file is found from a nested directory
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        let cmd = cmd
+            .current_dir(templates_path.join("project-1"))
+            .arg("transform");
+
+        cmd.assert().success();
+
+        cmd.assert()
+            .stdout(predicate::str::contains("Loaded 6 template file(s)").from_utf8());
+
+        cmd.assert()
+            .stdout(predicate::str::contains("Loaded 1 config file(s)").from_utf8());
+
+        // Flags override the project file
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        let cmd =
+            cmd.current_dir(&templates_path)
+                .args(["transform", "--environments-filter", ".+"]);
+
+        cmd.assert().success();
+
+        assert!(!dir_diff::is_different(
+            templates_path.join("project-1"),
+            Path::new("tests/fixtures/projects/rendered/project-1")
+        )
+        .unwrap());
+
+        assert!(!dir_diff::is_different(
+            templates_path.join("project-2"),
+            Path::new("tests/fixtures/projects/rendered/project-2")
+        )
+        .unwrap());
+    }
 }
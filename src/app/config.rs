@@ -1,7 +1,15 @@
-use anyhow::Result;
-use hogan::config::ConfigUrl;
+use crate::app::project::PROJECT_FILE;
+use anyhow::{Context, Result};
+use hogan::auth::{GitAuth, HttpsCredentials};
+use hogan::config::{ConfigUrl, Mirror};
+use hogan::error::HoganError;
+use hogan::git::{CloneOptions, NativeGit};
+use hogan::signature::AllowedSigners;
+use hogan::ssh::{HostKeyChecking, SshOptions};
 use regex::{Regex, RegexBuilder};
+use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
@@ -25,6 +33,9 @@ pub enum AppCommand {
         #[structopt(flatten)]
         common: AppCommon,
 
+        #[structopt(flatten)]
+        mirror: AppMirror,
+
         /// Filter environments to render templates for
         #[structopt(
             short = "e",
@@ -55,9 +66,119 @@ pub enum AppCommand {
         )]
         templates_regex: Regex,
 
+        /// Also skip template and config files ignored by .gitignore files. Files matched by a
+        /// .hoganignore are always skipped
+        #[structopt(long = "gitignore", overrides_with = "no-gitignore")]
+        gitignore: bool,
+
+        /// Don't skip the files ignored by .gitignore files when the project file turns it on
+        #[structopt(long = "no-gitignore", overrides_with = "gitignore")]
+        no_gitignore: bool,
+
         /// Ignore existing config files intead of overwriting
         #[structopt(short = "i", long = "ignore-existing")]
         ignore_existing: bool,
+
+        /// Delete previously generated files which no longer match an environment and template.
+        /// Every transform tracks the files it generates in a manifest in the templates directory
+        #[structopt(long = "prune")]
+        prune: bool,
+
+        /// List the stale files that would be pruned without deleting them
+        #[structopt(long = "dry-run", requires = "prune")]
+        dry_run: bool,
+
+        /// Render every template before writing any files and report all render errors together.
+        /// Nothing is written if any template fails to render
+        #[structopt(long = "all-errors")]
+        all_errors: bool,
+
+        /// Number of templates to render in parallel. Defaults to the number of available CPUs
+        #[structopt(short = "j", long = "jobs", value_name = "JOBS")]
+        jobs: Option<usize>,
+
+        /// Lock git configs to their current head instead of the SHA in the templates hogan.lock
+        #[structopt(long = "update")]
+        update: bool,
+    },
+    /// Lock the git configs used by transform to the head of a branch
+    #[structopt(name = "lock")]
+    Lock {
+        #[structopt(flatten)]
+        common: AppCommon,
+
+        #[structopt(flatten)]
+        mirror: AppMirror,
+
+        /// Templates directory to write the hogan.lock to
+        #[structopt(
+            short = "t",
+            long = "templates",
+            parse(from_os_str),
+            default_value = ".",
+            value_name = "DIR"
+        )]
+        templates_path: PathBuf,
+
+        /// Branch to lock to. Defaults to the branch of the configs URL
+        #[structopt(short = "b", long = "branch", value_name = "BRANCH")]
+        branch: Option<String>,
+    },
+    /// Report the config keys referenced by templates and the environments missing them
+    #[structopt(name = "coverage")]
+    Coverage {
+        #[structopt(flatten)]
+        common: AppCommon,
+
+        #[structopt(flatten)]
+        mirror: AppMirror,
+
+        /// Filter environments to check templates against
+        #[structopt(
+            short = "e",
+            long = "environments-filter",
+            parse(try_from_str = App::parse_regex),
+            default_value = ".+",
+            value_name = "REGEX"
+        )]
+        environments_regex: Regex,
+
+        /// Template source (recursive)
+        #[structopt(
+            short = "t",
+            long = "templates",
+            parse(from_os_str),
+            default_value = ".",
+            value_name = "DIR"
+        )]
+        templates_path: PathBuf,
+
+        /// Filter templates to analyze
+        #[structopt(
+            short = "f",
+            long = "templates-filter",
+            parse(try_from_str = App::parse_regex),
+            default_value = "^[^.]*(\\w+\\.)*template([-.].+)?\\.(config|ya?ml|properties)",
+            value_name = "REGEX"
+        )]
+        templates_regex: Regex,
+
+        /// Also skip template and config files ignored by .gitignore files. Files matched by a
+        /// .hoganignore are always skipped
+        #[structopt(long = "gitignore", overrides_with = "no-gitignore")]
+        gitignore: bool,
+
+        /// Don't skip the files ignored by .gitignore files when the project file turns it on
+        #[structopt(long = "no-gitignore", overrides_with = "gitignore")]
+        no_gitignore: bool,
+
+        /// List every key referenced by each template
+        #[structopt(long = "list-keys")]
+        list_keys: bool,
+
+        /// Exit with an error if any environment is missing a key
+        #[structopt(long = "check")]
+        check: bool,
     },
     /// Respond to HTTP requests to transform a template
     #[structopt(name = "server")]
@@ -125,19 +246,85 @@ pub enum AppCommand {
         )]
         fetch_poller: u64,
 
+        ///The longest to wait between background fetches while backing off after failed fetches
+        #[structopt(
+            long = "fetch-max-backoff",
+            default_value = "300",
+            value_name = "SECONDS"
+        )]
+        fetch_max_backoff: u64,
+
+        ///How often the background poller runs maintenance on the git repo. It runs sooner when the repo has grown by
+        ///half since the last maintenance
+        #[structopt(
+            long = "maintenance-interval",
+            default_value = "900",
+            value_name = "SECONDS"
+        )]
+        maintenance_interval: u64,
+
         ///If enabled, will allow requests to trigger fetches against the git repo. Otherwise a background poller will perform the fetches
         ///on a set interval
         #[structopt(short, long)]
         allow_fetch: bool,
+
+        ///Read environments straight from the git objects of a SHA instead of checking it out, so requests for different SHAs
+        ///are not serialized behind a single working directory
+        #[structopt(long = "tree-reads")]
+        tree_reads: bool,
+
+        ///Read-only mirrors of the git configs to fetch from, in order, when fetching from the configs url fails. May be
+        ///given several times
+        #[structopt(long = "fallback-remote", value_name = "URL")]
+        fallback_remotes: Vec<ConfigUrl>,
+
+        ///Shared secret of the POST /hooks/git push webhook, which is only served when a secret is set
+        #[structopt(
+            long = "webhook-secret",
+            env = "HOGAN_WEBHOOK_SECRET",
+            hide_env_values = true,
+            value_name = "SECRET"
+        )]
+        webhook_secret: Option<String>,
+
+        ///File containing the shared secret of the push webhook
+        #[structopt(
+            long = "webhook-secret-file",
+            parse(from_str = App::parse_path_buf),
+            value_name = "FILE"
+        )]
+        webhook_secret_file: Option<PathBuf>,
+
+        ///How long to wait after a push webhook before fetching, so a burst of pushes is fetched once
+        #[structopt(
+            long = "webhook-debounce",
+            default_value = "2000",
+            value_name = "MILLISECONDS"
+        )]
+        webhook_debounce: u64,
+
+        ///How long a request for the head of a branch waits for the lookup before failing
+        #[structopt(long = "head-timeout", default_value = "60", value_name = "SECONDS")]
+        head_timeout: u64,
+
+        ///How long the head of a branch is reused for later requests, unless a fetch moves a ref sooner. 0 turns the cache
+        ///off
+        #[structopt(
+            long = "head-cache-ttl",
+            default_value = "2000",
+            value_name = "MILLISECONDS"
+        )]
+        head_cache_ttl: u64,
     },
 }
 
 #[derive(StructOpt, Debug, Clone)]
 pub struct AppCommon {
     /// Config source. Accepts file and git URLs. Paths within a git repository may be appended
-    /// to a git URL, and branches may be specified as a URL fragment (recursive if applicable)
+    /// to a git URL, and branches may be specified as a URL fragment (recursive if applicable).
+    /// Required unless set in the .hogan.toml project file of the templates directory
     #[structopt(short = "c", long = "configs", value_name = "URL")]
-    pub configs_url: ConfigUrl,
+    pub configs_url: Option<ConfigUrl>,
 
     /// SSH key to use if configs URL requires authentication
     #[structopt(
@@ -149,23 +336,247 @@ pub struct AppCommon {
     )]
     pub ssh_key: PathBuf,
 
+    /// Passphrase of the SSH key
+    #[structopt(
+        long = "ssh-passphrase",
+        env = "HOGAN_SSH_PASSPHRASE",
+        hide_env_values = true,
+        value_name = "PASSPHRASE"
+    )]
+    pub ssh_passphrase: Option<String>,
+
+    /// File containing the passphrase of the SSH key
+    #[structopt(
+        long = "ssh-passphrase-file",
+        parse(from_str = App::parse_path_buf),
+        value_name = "FILE"
+    )]
+    pub ssh_passphrase_file: Option<PathBuf>,
+
+    /// Authenticate with the keys of a running ssh-agent before the SSH key
+    #[structopt(long = "ssh-agent")]
+    pub ssh_agent: bool,
+
+    /// User to authenticate with HTTPS git configs as. Defaults to the user in the configs URL
+    #[structopt(long = "https-user", env = "HOGAN_GIT_USER", value_name = "USER")]
+    pub https_user: Option<String>,
+
+    /// Password or access token for HTTPS git configs
+    #[structopt(
+        long = "https-token",
+        env = "HOGAN_GIT_TOKEN",
+        hide_env_values = true,
+        value_name = "TOKEN"
+    )]
+    pub https_token: Option<String>,
+
+    /// File containing the password or access token for HTTPS git configs
+    #[structopt(
+        long = "https-token-file",
+        parse(from_str = App::parse_path_buf),
+        value_name = "FILE"
+    )]
+    pub https_token_file: Option<PathBuf>,
+
+    /// Ask the credential helpers in the git config for HTTPS credentials when no others work
+    #[structopt(long = "credential-helper")]
+    pub credential_helper: bool,
+
+    /// File the SSH host keys of git configs are checked against
+    #[structopt(
+        long = "known-hosts",
+        parse(from_str = App::parse_path_buf),
+        default_value = "~/.ssh/known_hosts",
+        value_name = "FILE"
+    )]
+    pub known_hosts: PathBuf,
+
+    /// Whether to check SSH host keys against the known hosts, refusing hosts which are not in it
+    /// (strict) or adding them (accept-new). Left to the SSH configuration when off
+    #[structopt(
+        long = "host-key-checking",
+        default_value = "off",
+        possible_values = &["off", "strict", "accept-new"],
+        value_name = "POLICY"
+    )]
+    pub host_key_checking: HostKeyChecking,
+
     /// Throw errors if values do not exist in configs
-    #[structopt(short = "s", long = "strict")]
+    #[structopt(short = "s", long = "strict", overrides_with = "no-strict")]
     pub strict: bool,
 
+    /// Turn off strict mode when the project file turns it on
+    #[structopt(long = "no-strict", overrides_with = "strict")]
+    pub no_strict: bool,
+
     /// When enabled the application will assume an external git executable is installed and
     /// available in the execution environment. This will enable maintenance on the underlying git repo
     /// This flag must be set to utilize the other native git operations (clone and fetch)
-    #[structopt(short = "g", long = "git")]
+    #[structopt(short = "g", long = "git", overrides_with = "no-native-git")]
     pub native_git: bool,
 
+    /// Don't use the external git executable when the project file turns it on
+    #[structopt(long = "no-git", overrides_with = "native-git")]
+    pub no_native_git: bool,
+
     /// When enabled the application will use the external git executable for performing fetches
-    #[structopt(long = "git-fetch")]
+    #[structopt(long = "git-fetch", overrides_with = "no-native-fetch")]
     pub native_fetch: bool,
 
+    /// Don't fetch with the external git executable when the project file turns it on
+    #[structopt(long = "no-git-fetch", overrides_with = "native-fetch")]
+    pub no_native_fetch: bool,
+
     /// When enabled the application will use the external git executable for performing clones
-    #[structopt(long = "git-clone")]
+    #[structopt(long = "git-clone", overrides_with = "no-native-clone")]
     pub native_clone: bool,
+
+    /// Don't clone with the external git executable when the project file turns it on
+    #[structopt(long = "no-git-clone", overrides_with = "native-clone")]
+    pub no_native_clone: bool,
+
+    /// Native git commands still running after this many seconds are stopped and fail
+    #[structopt(long = "git-timeout", default_value = "300", value_name = "SECONDS")]
+    pub git_timeout: u64,
+
+    /// Only clone this many commits of history from git configs. Requires native git clones
+    #[structopt(long = "depth", value_name = "COMMITS")]
+    pub depth: Option<u32>,
+
+    /// Clone git configs without file contents, which are fetched as they are checked out.
+    /// Requires native git clones
+    #[structopt(long = "blobless")]
+    pub blobless: bool,
+
+    /// Only check out the path of the configs within a git configs repository
+    #[structopt(long = "sparse")]
+    pub sparse: bool,
+
+    /// Recursively check out the submodules of git configs with each commit
+    #[structopt(long = "submodules")]
+    pub submodules: bool,
+
+    /// SSH allowed signers file. When this or a GnuPG keyring is given, config commits are only
+    /// rendered when they are signed by one of the allowed keys
+    #[structopt(
+        long = "allowed-signers",
+        parse(from_str = App::parse_path_buf),
+        value_name = "FILE"
+    )]
+    pub allowed_signers: Option<PathBuf>,
+
+    /// GnuPG home directory with the public keys config commits may be signed with
+    #[structopt(
+        long = "gpg-keyring",
+        parse(from_str = App::parse_path_buf),
+        value_name = "DIR"
+    )]
+    pub gpg_keyring: Option<PathBuf>,
+}
+
+/// Options for the persistent mirror git configs are cloned from by the command line
+#[derive(StructOpt, Debug, Clone)]
+pub struct AppMirror {
+    /// Directory to keep mirrors of git configs in, so each run only fetches new commits
+    #[structopt(
+        long = "cache-dir",
+        parse(from_str = App::parse_path_buf),
+        default_value = "~/.cache/hogan",
+        value_name = "DIR"
+    )]
+    pub cache_dir: PathBuf,
+
+    /// Use the cached mirror of git configs without contacting the remote
+    #[structopt(long = "offline", conflicts_with = "no-cache-dir")]
+    pub offline: bool,
+
+    /// Clone git configs straight from the remote, without keeping a mirror
+    #[structopt(long = "no-cache-dir")]
+    pub no_cache_dir: bool,
+}
+
+impl AppMirror {
+    pub fn mirror(&self) -> Option<Mirror> {
+        if self.no_cache_dir {
+            return None;
+        }
+        Some(Mirror {
+            cache_dir: self.cache_dir.clone(),
+            offline: self.offline,
+        })
+    }
+}
+
+impl AppCommon {
+    pub fn git_auth(&self) -> Result<GitAuth> {
+        Ok(GitAuth {
+            ssh: SshOptions {
+                key: self.ssh_key.clone(),
+                passphrase: read_secret(&self.ssh_passphrase, &self.ssh_passphrase_file)?,
+                agent: self.ssh_agent,
+                known_hosts: Some(self.known_hosts.clone()),
+                host_key_checking: self.host_key_checking,
+            },
+            https: HttpsCredentials {
+                username: self.https_user.clone(),
+                password: read_secret(&self.https_token, &self.https_token_file)?,
+                credential_helper: self.credential_helper,
+            },
+        })
+    }
+
+    pub fn git_runner(&self) -> NativeGit {
+        NativeGit {
+            timeout: Some(Duration::from_secs(self.git_timeout)),
+            ..Default::default()
+        }
+    }
+
+    pub fn clone_options(&self) -> CloneOptions {
+        CloneOptions {
+            depth: self.depth,
+            blobless: self.blobless,
+            sparse: self.sparse,
+            submodules: self.submodules,
+        }
+    }
+
+    /// The keys config commits must be signed with, if they must be signed at all
+    pub fn allowed_signers(&self) -> Option<AllowedSigners> {
+        if self.allowed_signers.is_none() && self.gpg_keyring.is_none() {
+            return None;
+        }
+        Some(AllowedSigners {
+            ssh: self.allowed_signers.clone(),
+            gpg: self.gpg_keyring.clone(),
+        })
+    }
+
+    pub fn configs_url(&self) -> Result<ConfigUrl> {
+        self.configs_url.clone().ok_or_else(|| {
+            HoganError::InvalidConfiguration {
+                param: "configs".to_string(),
+                msg: format!(
+                    "A configs URL must be passed with --configs or set in {}",
+                    PROJECT_FILE
+                ),
+            }
+            .into()
+        })
+    }
+}
+
+// A secret is read from its file when one is given, ignoring the trailing newline
+pub fn read_secret(secret: &Option<String>, file: &Option<PathBuf>) -> Result<Option<String>> {
+    match file {
+        Some(path) => Ok(Some(
+            fs::read_to_string(path)
+                .with_context(|| format!("Unable to read secret from {:?}", path))?
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_owned(),
+        )),
+        None => Ok(secret.clone()),
+    }
 }
 
 impl App {
@@ -0,0 +1,175 @@
+use crate::app::config::{App, AppCommon, AppMirror};
+use crate::app::lock::use_lock;
+use anyhow::Result;
+use hogan::analysis::KeyUsage;
+use hogan::config::ConfigDir;
+use hogan::error::HoganError;
+use hogan::template::TemplateDir;
+use regex::Regex;
+use std::path::PathBuf;
+
+#[allow(clippy::too_many_arguments)]
+pub fn coverage(
+    templates_path: PathBuf,
+    environments_regex: Regex,
+    templates_regex: Regex,
+    common: AppCommon,
+    mirror: AppMirror,
+    gitignore: bool,
+    list_keys: bool,
+    check: bool,
+) -> Result<()> {
+    let template_dir = TemplateDir::new(templates_path.clone(), gitignore)?;
+    let templates = template_dir.find(templates_regex);
+    println!("Loaded {} template file(s)", templates.len());
+
+    let configs_url = common.configs_url()?;
+    let mut config_dir = ConfigDir::new(
+        configs_url.clone(),
+        &common.git_auth()?,
+        common.native_git,
+        common.native_fetch,
+        common.native_clone,
+        common.git_runner(),
+        common.clone_options(),
+        mirror.mirror().as_ref(),
+    )?;
+    config_dir.set_gitignore(gitignore);
+    use_lock(&mut config_dir, &configs_url, &templates_path)?;
+    let environments = config_dir.find(App::config_regex(&environments_regex)?);
+    println!("Loaded {} config file(s)", environments.len());
+
+    let report = hogan::analysis::coverage(&templates, &environments)?;
+
+    if list_keys {
+        for template in &report {
+            println!(
+                "\n{:?} references {} key(s)",
+                template.template,
+                template.keys.len()
+            );
+            for key in &template.keys {
+                println!("  {}", key);
+            }
+        }
+    }
+
+    // Matrix of missing key counts, one row per template and one column per environment
+    println!("\nMissing keys by template and environment");
+    let mut header = vec!["Template".to_string()];
+    header.extend(environments.iter().map(|e| e.environment.clone()));
+    let mut rows = vec![header];
+    for template in &report {
+        let mut row = vec![template.template.to_string_lossy().into_owned()];
+        row.extend(template.environments.iter().map(|e| {
+            if e.missing.is_empty() {
+                "-".to_string()
+            } else {
+                e.missing.len().to_string()
+            }
+        }));
+        rows.push(row);
+    }
+    print_table(&rows);
+
+    let mut total_missing = 0;
+    for template in &report {
+        for environment in template
+            .environments
+            .iter()
+            .filter(|e| !e.missing.is_empty())
+        {
+            total_missing += environment.missing.len();
+            println!(
+                "\n{:?} is missing {} key(s) in {}",
+                template.template,
+                environment.missing.len(),
+                environment.environment
+            );
+            for key in &environment.missing {
+                match key.usage {
+                    KeyUsage::Value => println!("  {} (fails strict mode)", key),
+                    KeyUsage::Param => println!("  {}", key),
+                }
+            }
+        }
+    }
+    println!("\nFound {} missing key(s)", total_missing);
+
+    if check && total_missing > 0 {
+        return Err(HoganError::UnknownError {
+            msg: format!("{} key(s) are missing from the configs", total_missing),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+fn print_table(rows: &[Vec<String>]) {
+    let columns = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let widths = (0..columns)
+        .map(|i| {
+            rows.iter()
+                .filter_map(|r| r.get(i))
+                .map(|c| c.len())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<usize>>();
+
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<String>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_cmd;
+    use predicates;
+
+    use self::assert_cmd::prelude::*;
+    use self::predicates::prelude::*;
+    use std::process::Command;
+
+    #[cfg(not(all(target_env = "msvc", target_arch = "x86_64")))]
+    #[test]
+    fn test_coverage() {
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        let cmd = cmd.args([
+            "coverage",
+            "--configs",
+            "tests/fixtures/configs",
+            "--templates",
+            "tests/fixtures/projects/templates/project-1",
+            "--list-keys",
+        ]);
+
+        cmd.assert().success().stdout(
+            predicate::str::contains("Memcache.Servers.[].Endpoint")
+                .and(predicate::str::contains("is missing 1 key(s) in EMPTY"))
+                .and(predicate::str::contains(
+                    "EnvironmentType (fails strict mode)",
+                ))
+                .from_utf8(),
+        );
+
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        let cmd = cmd.args([
+            "coverage",
+            "--configs",
+            "tests/fixtures/configs",
+            "--templates",
+            "tests/fixtures/projects/templates/project-1",
+            "--check",
+        ]);
+
+        cmd.assert().failure();
+    }
+}
@@ -59,7 +59,6 @@ impl DdMetrics {
             .unwrap_or_else(|err| self.error_msg(name, &err.to_string()));
     }
 
-    #[allow(dead_code)]
     pub fn gauge(&self, name: &str, additional_tags: Option<Vec<String>>, value: &str) {
         if !self.enabled {
             return;
@@ -102,8 +101,13 @@ pub enum CustomMetrics {
     RequestTime,
     FetchTime,
     FetchCounter,
+    RemoteFetch,
+    HeadQuery,
+    HeadQueue,
     MaintenanceTime,
+    NativeGitTime,
     DbCleanup,
+    TemplateCache,
 }
 
 impl From<CustomMetrics> for &str {
@@ -113,8 +117,13 @@ impl From<CustomMetrics> for &str {
             CustomMetrics::RequestTime => "hogan.requests",
             CustomMetrics::FetchTime => "hogan.fetch",
             CustomMetrics::FetchCounter => "hogan.fetchcounter",
+            CustomMetrics::RemoteFetch => "hogan.remotefetch",
+            CustomMetrics::HeadQuery => "hogan.headquery",
+            CustomMetrics::HeadQueue => "hogan.headqueue",
             CustomMetrics::MaintenanceTime => "hogan.maintenance",
+            CustomMetrics::NativeGitTime => "hogan.nativegit",
             CustomMetrics::DbCleanup => "hogan.dbcleanup",
+            CustomMetrics::TemplateCache => "hogan.templatecache",
         }
     }
 }
@@ -1,8 +1,12 @@
 #![allow(clippy::from_over_into)]
 
 use crate::app::datadogstatsd::{CustomMetrics, DdMetrics};
-use hogan::config::ConfigDir;
+use crate::app::head_actor::HeadCache;
+use hogan::config::{ConfigDir, FetchGate, FetchHooks, FetchObserver};
+use hogan::error::HoganError;
+use parking_lot::Mutex;
 use riker::actors::*;
+use serde::Serialize;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::SystemTime;
@@ -10,24 +14,99 @@ use std::time::SystemTime;
 #[derive(Debug, Clone)]
 pub struct ExecuteFetch {}
 
-#[actor(ExecuteFetch)]
+/// Asks for a fetch after the debounce delay. Requests made while one is pending share its fetch
+#[derive(Debug, Clone)]
+pub struct RequestFetch {}
+
+/// Fetch failures in a row after which the circuit opens
+const FAILURE_THRESHOLD: u32 = 3;
+/// Growth of the object database since the last maintenance which brings the next one forward
+const MIN_MAINTENANCE_GROWTH: u64 = 8 * 1024 * 1024;
+
+/// State of the circuit breaker in front of the git server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    /// Fetches run on every poll, backing off after a failure
+    Closed,
+    /// Fetches have failed repeatedly, and are paused until the backoff has passed
+    Open,
+    /// The backoff has passed, and the fetch running now decides whether the circuit closes
+    HalfOpen,
+}
+
+/// How fetches from the git server are faring, as reported by the health route
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FetchHealth {
+    pub circuit: CircuitState,
+    pub consecutive_failures: u32,
+    /// Seconds since the epoch
+    pub last_success: Option<u64>,
+    /// Seconds since the epoch
+    pub last_failure: Option<u64>,
+    pub last_error: Option<String>,
+    /// Seconds since the epoch of the first poll which will fetch again while backing off
+    pub retry_at: Option<u64>,
+}
+
+impl Default for FetchHealth {
+    fn default() -> Self {
+        FetchHealth {
+            circuit: CircuitState::Closed,
+            consecutive_failures: 0,
+            last_success: None,
+            last_failure: None,
+            last_error: None,
+            retry_at: None,
+        }
+    }
+}
+
+/// When the poller fetches, backs off and maintains the repo
+#[derive(Debug, Clone)]
+pub struct FetchSchedule {
+    pub poll: Duration,
+    pub debounce: Duration,
+    pub max_backoff: Duration,
+    pub maintenance_interval: Duration,
+}
+
+#[actor(ExecuteFetch, RequestFetch)]
 #[derive(Debug)]
 struct FetchActor {
     config: Arc<ConfigDir>,
     last_updated: SystemTime,
     metrics: Arc<DdMetrics>,
-    fetch_delay: u64,
-    maintenance_count: u8,
+    schedule: FetchSchedule,
+    health: Arc<Mutex<FetchHealth>>,
+    // Polls before this time are skipped
+    retry_at: Option<SystemTime>,
+    last_maintenance: SystemTime,
+    maintained_size: Option<u64>,
+    fetch_requested: bool,
 }
 
-impl ActorFactoryArgs<(Arc<ConfigDir>, Arc<DdMetrics>, u64)> for FetchActor {
-    fn create_args((config, metrics, fetch_delay): (Arc<ConfigDir>, Arc<DdMetrics>, u64)) -> Self {
+type FetchActorArgs = (
+    Arc<ConfigDir>,
+    Arc<DdMetrics>,
+    FetchSchedule,
+    Arc<Mutex<FetchHealth>>,
+);
+
+impl ActorFactoryArgs<FetchActorArgs> for FetchActor {
+    fn create_args((config, metrics, schedule, health): FetchActorArgs) -> Self {
+        let maintained_size = config.repository_size().ok();
         FetchActor {
             config,
             last_updated: SystemTime::now(),
             metrics,
-            fetch_delay,
-            maintenance_count: 0,
+            schedule,
+            health,
+            retry_at: None,
+            last_maintenance: SystemTime::now(),
+            maintained_size,
+            fetch_requested: false,
         }
     }
 }
@@ -44,8 +123,22 @@ impl Receive<ExecuteFetch> for FetchActor {
     type Msg = FetchActorMsg;
 
     fn receive(&mut self, _ctx: &Context<Self::Msg>, _msg: ExecuteFetch, _sender: Sender) {
+        if let Some(retry_at) = self.retry_at {
+            if SystemTime::now() < retry_at {
+                debug!("Backing off fetch after a failure");
+                return;
+            }
+            let mut health = self.health.lock();
+            if health.circuit == CircuitState::Open {
+                info!("Retrying fetch after backing off");
+                health.circuit = CircuitState::HalfOpen;
+            }
+        }
+        // Any fetch picks up what was pushed before it started
+        self.fetch_requested = false;
+
         let start_time = SystemTime::now();
-        let fetch_result = self.config.fetch_only("origin");
+        let fetch_result = self.config.fetch_with_fallback("origin");
         if let Ok(elapsed_time) = start_time.elapsed() {
             if let Err(e) = &fetch_result {
                 warn!(
@@ -72,7 +165,7 @@ impl Receive<ExecuteFetch> for FetchActor {
             }
             let counter_tags = if fetch_result.is_err() {
                 vec!["time:error".to_string()]
-            } else if elapsed_time.as_millis() <= self.fetch_delay as u128 {
+            } else if elapsed_time <= self.schedule.poll {
                 vec!["time:under".to_string()]
             } else {
                 vec!["time:over".to_string()]
@@ -81,56 +174,403 @@ impl Receive<ExecuteFetch> for FetchActor {
             self.metrics
                 .incr(CustomMetrics::FetchCounter.into(), Some(counter_tags));
         }
-        if self.maintenance_count >= 10 {
-            self.maintenance_count = 0;
-            let maintenance_start = SystemTime::now();
-            let maintenance_result = self.config.perform_maintenance();
-            if let Ok(elapsed_time) = maintenance_start.elapsed() {
-                if let Err(e) = &maintenance_result {
-                    warn!(
-                        "Unable to perform maintenance on git repo. Took {} ms. Error: {:?}",
-                        elapsed_time.as_millis(),
-                        e
-                    );
-                } else {
-                    info!(
-                        "Performed maintenance on repo took: {} ms",
-                        elapsed_time.as_millis()
-                    );
-                }
-                self.metrics.time(
-                    CustomMetrics::MaintenanceTime.into(),
-                    None,
-                    elapsed_time.as_millis() as i64,
-                );
+
+        match fetch_result {
+            Ok(_) => {
+                self.record_success();
+                self.maintain();
             }
-        } else {
-            self.maintenance_count += 1
+            Err(e) => self.record_failure(&e),
         }
 
         self.last_updated = SystemTime::now();
     }
 }
 
+impl Receive<RequestFetch> for FetchActor {
+    type Msg = FetchActorMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: RequestFetch, _sender: Sender) {
+        if self.fetch_requested {
+            debug!("A requested fetch is already pending");
+            return;
+        }
+        self.fetch_requested = true;
+        // A request made while backing off is fetched once the backoff has passed
+        let delay = self
+            .retry_at
+            .and_then(|retry_at| retry_at.duration_since(SystemTime::now()).ok())
+            .map_or(self.schedule.debounce, |wait| {
+                wait.max(self.schedule.debounce)
+            });
+        ctx.schedule_once(delay, ctx.myself(), None, ExecuteFetch {});
+    }
+}
+
+impl FetchActor {
+    fn record_success(&mut self) {
+        let mut health = self.health.lock();
+        if health.consecutive_failures > 0 {
+            info!(
+                "Fetched after {} failed attempts",
+                health.consecutive_failures
+            );
+        }
+        health.circuit = CircuitState::Closed;
+        health.consecutive_failures = 0;
+        health.last_success = epoch_secs(SystemTime::now());
+        health.retry_at = None;
+        self.retry_at = None;
+    }
+
+    fn record_failure(&mut self, e: &anyhow::Error) {
+        let mut health = self.health.lock();
+        health.consecutive_failures += 1;
+        let delay = backoff(
+            self.schedule.poll,
+            self.schedule.max_backoff,
+            health.consecutive_failures,
+            rand::random(),
+        );
+        let now = SystemTime::now();
+        let retry_at = now + delay;
+        if health.consecutive_failures >= FAILURE_THRESHOLD {
+            if health.circuit == CircuitState::Closed {
+                warn!(
+                    "Pausing fetches after {} failures in a row",
+                    health.consecutive_failures
+                );
+            }
+            health.circuit = CircuitState::Open;
+        }
+        health.last_failure = epoch_secs(now);
+        health.last_error = Some(format!("{:#}", e));
+        health.retry_at = epoch_secs(retry_at);
+        self.retry_at = Some(retry_at);
+        info!("Backing off fetches for {} ms", delay.as_millis());
+    }
+
+    // Maintenance prunes against origin, so it only runs once the git server has been reached
+    fn maintain(&mut self) {
+        let elapsed = self.last_maintenance.elapsed().unwrap_or_default();
+        let size = self.config.repository_size().ok();
+        if !maintenance_due(
+            elapsed,
+            self.schedule.maintenance_interval,
+            size,
+            self.maintained_size,
+        ) {
+            return;
+        }
+
+        let maintenance_start = SystemTime::now();
+        let maintenance_result = self.config.perform_maintenance();
+        if let Ok(elapsed_time) = maintenance_start.elapsed() {
+            if let Err(e) = &maintenance_result {
+                warn!(
+                    "Unable to perform maintenance on git repo. Took {} ms. Error: {:?}",
+                    elapsed_time.as_millis(),
+                    e
+                );
+            } else {
+                info!(
+                    "Performed maintenance on repo took: {} ms",
+                    elapsed_time.as_millis()
+                );
+            }
+            self.metrics.time(
+                CustomMetrics::MaintenanceTime.into(),
+                None,
+                elapsed_time.as_millis() as i64,
+            );
+        }
+        self.last_maintenance = SystemTime::now();
+        self.maintained_size = self.config.repository_size().ok().or(size);
+    }
+}
+
+/// Hooks for every fetch of the config repository, whether made by the poller or for a request.
+/// Fetches are refused while the circuit is open, each one is counted by remote and result, and
+/// the cached branch heads are cleared once a fetch moves any ref
+pub fn fetch_hooks(
+    health: Arc<Mutex<FetchHealth>>,
+    metrics: Arc<DdMetrics>,
+    head_cache: Arc<HeadCache>,
+) -> FetchHooks {
+    let gate: FetchGate = Arc::new(move |_| {
+        let health = health.lock();
+        if health.circuit == CircuitState::Open {
+            return Err(HoganError::GitError {
+                msg: format!(
+                    "Fetches are paused after {} failures in a row",
+                    health.consecutive_failures
+                ),
+            }
+            .into());
+        }
+        Ok(())
+    });
+    let observer: FetchObserver = Arc::new(move |config, remote, elapsed, success| {
+        let status = if success { "ok" } else { "error" };
+        metrics.incr(
+            CustomMetrics::RemoteFetch.into(),
+            Some(vec![
+                format!("remote:{}", remote),
+                format!("result:{}", status),
+            ]),
+        );
+        if success {
+            if remote != "origin" {
+                info!(
+                    "Fetched from fallback remote {} in {} ms",
+                    remote,
+                    elapsed.as_millis()
+                );
+            }
+            head_cache.check_refs(config.ref_targets().ok());
+        }
+    });
+    FetchHooks {
+        gate: Some(gate),
+        observer: Some(observer),
+    }
+}
+
+/// How long to wait after the given number of failed fetches in a row. The first failure waits one
+/// poll, and the delay doubles with each failure after it up to `max`, and up to half of it is taken off at random by `jitter`, a number in
+/// `[0, 1)`, so that servers which lost the git server together do not all retry together
+fn backoff(poll: Duration, max: Duration, failures: u32, jitter: f64) -> Duration {
+    let delay = poll
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(max);
+    delay.mul_f64(1.0 - jitter / 2.0)
+}
+
+/// Maintenance is due once the interval has passed, or sooner when the object database has grown
+/// by half since the last maintenance
+fn maintenance_due(
+    elapsed: Duration,
+    interval: Duration,
+    size: Option<u64>,
+    maintained_size: Option<u64>,
+) -> bool {
+    if elapsed >= interval {
+        return true;
+    }
+    match (size, maintained_size) {
+        (Some(size), Some(maintained_size)) => {
+            size >= maintained_size + (maintained_size / 2).max(MIN_MAINTENANCE_GROWTH)
+        }
+        _ => false,
+    }
+}
+
+fn epoch_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .map(|since| since.as_secs())
+}
+
 pub fn init_system(
     system: &ActorSystem,
     config: Arc<ConfigDir>,
     metrics: Arc<DdMetrics>,
-    fetch_poller_delay: u64,
-) {
+    schedule: FetchSchedule,
+    health: Arc<Mutex<FetchHealth>>,
+) -> ActorRef<FetchActorMsg> {
+    let poll = schedule.poll;
     let worker = system
-        .actor_of_args::<FetchActor, _>("repo-fetch-worker", (config, metrics, fetch_poller_delay))
+        .actor_of_args::<FetchActor, _>("repo-fetch-worker", (config, metrics, schedule, health))
         .unwrap();
 
-    system.schedule(
-        Duration::from_millis(fetch_poller_delay),
-        Duration::from_millis(fetch_poller_delay),
-        worker.clone(),
-        None,
-        ExecuteFetch {},
-    );
+    system.schedule(poll, poll, worker.clone(), None, ExecuteFetch {});
 
-    info!("Scheduled fetch poller for every {} ms", fetch_poller_delay);
+    info!("Scheduled fetch poller for every {} ms", poll.as_millis());
 
     worker.tell(ExecuteFetch {}, None);
+    worker
+}
+
+/// Fetches soon, rather than waiting for the poller
+pub fn request_fetch(worker: &ActorRef<FetchActorMsg>) {
+    worker.tell(RequestFetch {}, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hogan::auth::GitAuth;
+    use hogan::config::ConfigUrl;
+    use hogan::git::{CloneOptions, NativeGit};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[test]
+    fn test_backoff() {
+        let poll = Duration::from_secs(10);
+        let max = Duration::from_secs(300);
+        assert_eq!(backoff(poll, max, 1, 0.0), poll);
+        assert_eq!(backoff(poll, max, 2, 0.0), Duration::from_secs(20));
+        assert_eq!(backoff(poll, max, 2, 0.5), Duration::from_secs(15));
+        assert_eq!(backoff(poll, max, 6, 0.0), max);
+        assert_eq!(backoff(poll, max, 40, 0.0), max);
+        assert!(backoff(poll, max, 40, 0.999) > max / 2);
+    }
+
+    #[test]
+    fn test_maintenance_due() {
+        let interval = Duration::from_secs(900);
+        let mb = 1024 * 1024;
+        let due = |elapsed, size, maintained_size| {
+            maintenance_due(
+                Duration::from_secs(elapsed),
+                interval,
+                size,
+                maintained_size,
+            )
+        };
+        assert!(due(900, None, None));
+        assert!(!due(60, None, Some(100 * mb)));
+        assert!(!due(60, Some(120 * mb), Some(100 * mb)));
+        assert!(due(60, Some(150 * mb), Some(100 * mb)));
+        // Small repos must grow by a minimum amount
+        assert!(!due(60, Some(4 * mb), Some(mb)));
+        assert!(due(60, Some(9 * mb), Some(mb)));
+    }
+
+    /// A git repository with one config, and a config dir cloned from it which runs `hooks`
+    fn config_dir(source_dir: &Path, hooks: FetchHooks) -> ConfigDir {
+        let repo = git2::Repository::init(source_dir).unwrap();
+        std::fs::create_dir(source_dir.join("configs")).unwrap();
+        std::fs::write(source_dir.join("configs/config.TEST.json"), "{}").unwrap();
+        let mut index = repo.index().unwrap();
+        index
+            .add_path(Path::new("configs/config.TEST.json"))
+            .unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("hogan", "hogan@example.com").unwrap();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Add configs",
+            &tree,
+            &[],
+        )
+        .unwrap();
+
+        let mut config_dir = ConfigDir::new(
+            ConfigUrl::Git {
+                url: url::Url::from_file_path(source_dir).unwrap(),
+                branch: None,
+                internal_path: PathBuf::from("configs"),
+            },
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
+        )
+        .unwrap();
+        config_dir.set_fetch_hooks(hooks);
+        config_dir
+    }
+
+    fn fetch_worker(
+        system: &ActorSystem,
+        config_dir: ConfigDir,
+        schedule: FetchSchedule,
+    ) -> ActorRef<FetchActorMsg> {
+        system
+            .actor_of_args::<FetchActor, _>(
+                "repo-fetch-worker",
+                (
+                    Arc::new(config_dir),
+                    Arc::new(DdMetrics::new(false)),
+                    schedule,
+                    Arc::new(Mutex::new(FetchHealth::default())),
+                ),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_requests_share_a_fetch() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let observed = fetches.clone();
+        let config_dir = config_dir(
+            source_dir.path(),
+            FetchHooks {
+                gate: None,
+                observer: Some(Arc::new(move |_, _, _, _| {
+                    observed.fetch_add(1, Ordering::SeqCst);
+                })),
+            },
+        );
+
+        let system = ActorSystem::new().unwrap();
+        let schedule = FetchSchedule {
+            poll: Duration::from_secs(3600),
+            debounce: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(3600),
+            maintenance_interval: Duration::from_secs(3600),
+        };
+        let worker = fetch_worker(&system, config_dir, schedule);
+
+        for _ in 0..5 {
+            request_fetch(&worker);
+        }
+        std::thread::sleep(Duration::from_secs(1));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // Once the fetch has run, a new request is fetched again
+        request_fetch(&worker);
+        std::thread::sleep(Duration::from_secs(1));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_request_waits_out_backoff() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let fail = Arc::new(AtomicBool::new(true));
+        let gate_fail = fail.clone();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let observed = fetches.clone();
+        let config_dir = config_dir(
+            source_dir.path(),
+            FetchHooks {
+                gate: Some(Arc::new(move |_| {
+                    if gate_fail.swap(false, Ordering::SeqCst) {
+                        anyhow::bail!("Git server is down");
+                    }
+                    Ok(())
+                })),
+                observer: Some(Arc::new(move |_, _, _, success| {
+                    if success {
+                        observed.fetch_add(1, Ordering::SeqCst);
+                    }
+                })),
+            },
+        );
+
+        let system = ActorSystem::new().unwrap();
+        let schedule = FetchSchedule {
+            poll: Duration::from_millis(500),
+            debounce: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(3600),
+            maintenance_interval: Duration::from_secs(3600),
+        };
+        let worker = fetch_worker(&system, config_dir, schedule);
+
+        // The first fetch fails, so the request comes in while backing off
+        worker.tell(ExecuteFetch {}, None);
+        std::thread::sleep(Duration::from_millis(100));
+        request_fetch(&worker);
+        std::thread::sleep(Duration::from_secs(1));
+        assert!(!fail.load(Ordering::SeqCst));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
 }
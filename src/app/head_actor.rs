@@ -1,20 +1,94 @@
 #![allow(clippy::from_over_into)]
 
+use crate::app::datadogstatsd::{CustomMetrics, DdMetrics};
 use anyhow::Result;
 use futures::executor::block_on;
 use futures::future::RemoteHandle;
 use hogan::config::ConfigDir;
 use hogan::error::HoganError;
+use parking_lot::Mutex;
 use riker::actors::*;
 use riker_patterns::ask;
 use std::collections::hash_map::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use uuid::Uuid;
 
 pub type HeadRequestActor = ActorRef<HeadRequestHolderMsg>;
 
+/// Branch heads found recently, so bursts of requests for the same branch are answered without a
+/// lookup. It is cleared when any fetch moves a ref
+#[derive(Debug)]
+pub struct HeadCache {
+    ttl: Duration,
+    heads: Mutex<CachedHeads>,
+    // Where each ref pointed after the last fetch, to tell when cached heads are out of date
+    refs: Mutex<Option<HashMap<String, String>>>,
+}
+
+#[derive(Debug, Default)]
+struct CachedHeads {
+    // Incremented on each clear, so lookups started before it are not cached after it
+    generation: u64,
+    shas: HashMap<String, (String, Instant)>,
+}
+
+impl HeadCache {
+    pub fn new(ttl: Duration) -> Self {
+        HeadCache {
+            ttl,
+            heads: Mutex::new(CachedHeads::default()),
+            refs: Mutex::new(None),
+        }
+    }
+
+    fn get(&self, branch: &str) -> Option<String> {
+        let heads = self.heads.lock();
+        heads
+            .shas
+            .get(branch)
+            .filter(|(_, found)| found.elapsed() < self.ttl)
+            .map(|(sha, _)| sha.to_owned())
+    }
+
+    fn generation(&self) -> u64 {
+        self.heads.lock().generation
+    }
+
+    fn insert(&self, branch: &str, sha: &str, generation: u64) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut heads = self.heads.lock();
+        if heads.generation != generation {
+            debug!("Not caching head of {} found before refs changed", branch);
+            return;
+        }
+        let ttl = self.ttl;
+        heads.shas.retain(|_, (_, found)| found.elapsed() < ttl);
+        heads
+            .shas
+            .insert(branch.to_owned(), (sha.to_owned(), Instant::now()));
+    }
+
+    pub fn clear(&self) {
+        let mut heads = self.heads.lock();
+        heads.generation += 1;
+        heads.shas.clear();
+    }
+
+    /// Clears the cache when the refs of the repository have moved since they were last checked
+    pub fn check_refs(&self, refs: Option<HashMap<String, String>>) {
+        let mut checked = self.refs.lock();
+        if refs.is_none() || refs != *checked {
+            debug!("Refs changed, clearing cached branch heads");
+            self.clear();
+        }
+        *checked = refs;
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct HeadQuery {
     branch: String,
@@ -67,19 +141,52 @@ impl From<HeadQuery> for HeadRequestHolderMsg {
 struct HeadRequestHolder {
     queries: HashMap<String, Vec<BasicActorRef>>,
     request_ids: HashMap<String, Uuid>,
+    // The cache generation each pending lookup started in
+    generations: HashMap<String, u64>,
     request_worker: ActorRef<HeadQueryWorkerMsg>,
+    timeout: Duration,
+    cache: Arc<HeadCache>,
+    metrics: Arc<DdMetrics>,
 }
 
-impl ActorFactoryArgs<ActorRef<HeadQueryWorkerMsg>> for HeadRequestHolder {
-    fn create_args(request_worker: ActorRef<HeadQueryWorkerMsg>) -> Self {
+type HeadRequestHolderArgs = (
+    ActorRef<HeadQueryWorkerMsg>,
+    Duration,
+    Arc<HeadCache>,
+    Arc<DdMetrics>,
+);
+
+impl ActorFactoryArgs<HeadRequestHolderArgs> for HeadRequestHolder {
+    fn create_args((request_worker, timeout, cache, metrics): HeadRequestHolderArgs) -> Self {
         HeadRequestHolder {
             queries: HashMap::new(),
             request_ids: HashMap::new(),
+            generations: HashMap::new(),
             request_worker,
+            timeout,
+            cache,
+            metrics,
         }
     }
 }
 
+impl HeadRequestHolder {
+    // Counts how each request was answered, so the share of coalesced and cached requests can be
+    // tracked
+    fn record_query(&self, result: &str) {
+        self.metrics.incr(
+            CustomMetrics::HeadQuery.into(),
+            Some(vec![format!("result:{}", result)]),
+        );
+    }
+
+    fn record_queue_depth(&self) {
+        let waiting: usize = self.queries.values().map(|requests| requests.len()).sum();
+        self.metrics
+            .gauge(CustomMetrics::HeadQueue.into(), None, &waiting.to_string());
+    }
+}
+
 fn get_request_id(holder: &mut HeadRequestHolder, branch: &str) -> Uuid {
     *holder
         .request_ids
@@ -117,15 +224,35 @@ impl Actor for HeadRequestHolder {
                 }
             }
             HeadRequestHolderMsg::HeadQuery(query) => {
+                if let Some(sha) = self.cache.get(&query.branch) {
+                    debug!("Found cached head for {}", query.branch);
+                    self.record_query("cache");
+                    let requester = sender.as_ref().unwrap();
+                    if requester
+                        .try_tell(Ok::<_, HoganError>(sha), Some(ctx.myself().into()))
+                        .is_err()
+                    {
+                        warn!(
+                            "Unable to return response to {:?} {}",
+                            requester, query.branch
+                        );
+                    }
+                    return;
+                }
+
                 if let Some(requests) = self.queries.get_mut(&query.branch) {
                     requests.push(sender.as_ref().unwrap().clone());
                     debug!("Added request to waiting pool {:?}", query);
+                    self.record_query("coalesced");
                 } else {
+                    self.record_query("lookup");
                     self.queries.insert(
                         query.branch.to_owned(),
                         vec![sender.as_ref().unwrap().clone()],
                     );
                     let request_id = get_request_id(self, &query.branch);
+                    self.generations
+                        .insert(query.branch.to_owned(), self.cache.generation());
 
                     let request = PerformQuery {
                         branch: query.branch.to_owned(),
@@ -139,13 +266,14 @@ impl Actor for HeadRequestHolder {
 
                     debug!("Starting head query request {:?}", request);
                     ctx.schedule_once(
-                        Duration::from_secs(60),
+                        self.timeout,
                         ctx.myself(),
                         Some(ctx.myself().into()),
                         timeout,
                     );
                     self.request_worker.tell(request, Some(ctx.myself().into()));
                 }
+                self.record_queue_depth();
             }
             HeadRequestHolderMsg::HeadResult(result) => {
                 if !check_request_id(self, &result.branch, &result.id) {
@@ -153,6 +281,11 @@ impl Actor for HeadRequestHolder {
                     return;
                 }
 
+                let generation = self.generations.remove(&result.branch);
+                if let (Ok(sha), Some(generation)) = (&result.sha, generation) {
+                    self.cache.insert(&result.branch, sha, generation);
+                }
+
                 if let Some(requests) = self.queries.get(&result.branch) {
                     for r in requests {
                         if r.try_tell(result.sha.clone(), Some(ctx.myself().into()))
@@ -169,6 +302,7 @@ impl Actor for HeadRequestHolder {
                     self.queries.remove(&result.branch);
                     self.request_ids.remove(&result.branch);
                 }
+                self.record_queue_depth();
             }
         }
     }
@@ -251,12 +385,15 @@ pub fn init_system(
     sys: &ActorSystem,
     config: Arc<ConfigDir>,
     allow_fetch: bool,
+    timeout: Duration,
+    cache: Arc<HeadCache>,
+    metrics: Arc<DdMetrics>,
 ) -> HeadRequestActor {
     let worker: ActorRef<HeadQueryWorkerMsg> = sys
         .actor_of_args::<HeadQueryWorker, _>("query_worker", (config, allow_fetch))
         .unwrap();
 
-    sys.actor_of_args::<HeadRequestHolder, _>("query_holder", worker)
+    sys.actor_of_args::<HeadRequestHolder, _>("query_holder", (worker, timeout, cache, metrics))
         .unwrap()
 }
 
@@ -275,3 +412,46 @@ pub fn request_branch_head(
 
     block_on(result).map_err(|e| e.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_head_cache() {
+        let cache = HeadCache::new(Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.insert("master", "abc", generation);
+        assert_eq!(cache.get("master"), Some("abc".to_string()));
+        assert_eq!(cache.get("develop"), None);
+
+        cache.clear();
+        assert_eq!(cache.get("master"), None);
+        // A lookup which started before the refs changed is not cached
+        cache.insert("master", "abc", generation);
+        assert_eq!(cache.get("master"), None);
+        cache.insert("master", "def", cache.generation());
+        assert_eq!(cache.get("master"), Some("def".to_string()));
+
+        // Only a fetch which moves a ref clears the cache
+        let refs = HashMap::from([("refs/heads/master".to_string(), "def".to_string())]);
+        cache.check_refs(Some(refs.clone()));
+        cache.insert("master", "def", cache.generation());
+        cache.check_refs(Some(refs));
+        assert_eq!(cache.get("master"), Some("def".to_string()));
+        cache.check_refs(Some(HashMap::from([(
+            "refs/heads/master".to_string(),
+            "123".to_string(),
+        )])));
+        assert_eq!(cache.get("master"), None);
+
+        let expired = HeadCache::new(Duration::from_millis(10));
+        expired.insert("master", "abc", expired.generation());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(expired.get("master"), None);
+
+        let disabled = HeadCache::new(Duration::ZERO);
+        disabled.insert("master", "abc", disabled.generation());
+        assert_eq!(disabled.get("master"), None);
+    }
+}
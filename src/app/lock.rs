@@ -0,0 +1,225 @@
+use crate::app::config::{AppCommon, AppMirror};
+use anyhow::Result;
+use hogan::auth::redact;
+use hogan::config::{ConfigDir, ConfigUrl};
+use hogan::lock::{Lock, LOCK_FILE_NAME};
+use std::path::{Path, PathBuf};
+
+pub fn lock(
+    templates_path: PathBuf,
+    common: AppCommon,
+    mirror: AppMirror,
+    branch: Option<String>,
+) -> Result<()> {
+    let configs_url = common.configs_url()?;
+    let (url, url_branch) = match configs_url {
+        ConfigUrl::Git {
+            ref url,
+            branch: ref url_branch,
+            ..
+        } => (redact(url), url_branch.clone()),
+        ConfigUrl::File { .. } => {
+            println!("Configs are not in git, so there is nothing to lock");
+            return Ok(());
+        }
+    };
+
+    let config_dir = ConfigDir::new(
+        configs_url,
+        &common.git_auth()?,
+        common.native_git,
+        common.native_fetch,
+        common.native_clone,
+        common.git_runner(),
+        common.clone_options(),
+        mirror.mirror().as_ref(),
+    )?;
+    let sha = match branch {
+        Some(ref branch) => config_dir.find_branch_head("origin", branch, false)?,
+        None => config_dir.head_sha().unwrap_or_default().to_owned(),
+    };
+
+    println!("Locking configs to {}", sha);
+    Lock {
+        configs: url,
+        branch: branch.or(url_branch),
+        sha,
+    }
+    .save(&templates_path)
+}
+
+/// Checks out the SHA a git config repository is locked to. The current head is locked instead
+/// when there is no lock or `update` is set. A lock for another repository or branch is left as
+/// it is, and the current head is used without locking it
+pub fn lock_configs(
+    config_dir: &mut ConfigDir,
+    configs_url: &ConfigUrl,
+    templates_path: &Path,
+    update: bool,
+) -> Result<()> {
+    let (url, branch) = match configs_url {
+        ConfigUrl::Git { url, branch, .. } => (redact(url), branch.clone()),
+        ConfigUrl::File { .. } => return Ok(()),
+    };
+
+    if !update && Lock::load(templates_path)?.is_some() {
+        if !use_lock(config_dir, configs_url, templates_path)? {
+            println!(
+                "Leaving {} unchanged. Pass --update to lock these configs instead",
+                LOCK_FILE_NAME
+            );
+        }
+        return Ok(());
+    }
+
+    let sha = config_dir.head_sha().unwrap_or_default().to_owned();
+    println!("Locking configs to {} in {}", sha, LOCK_FILE_NAME);
+    Lock {
+        configs: url,
+        branch,
+        sha,
+    }
+    .save(templates_path)
+}
+
+/// Checks out the SHA a git config repository is locked to, if the lock is for the same
+/// repository and branch. A configs URL without a branch uses a lock taken from any branch
+pub fn use_lock(
+    config_dir: &mut ConfigDir,
+    configs_url: &ConfigUrl,
+    templates_path: &Path,
+) -> Result<bool> {
+    let (url, branch) = match configs_url {
+        ConfigUrl::Git { url, branch, .. } => (redact(url), branch),
+        ConfigUrl::File { .. } => return Ok(false),
+    };
+
+    match Lock::load(templates_path)? {
+        Some(lock) if lock.configs == url && (branch.is_none() || *branch == lock.branch) => {
+            println!("Using configs at {} from {}", lock.sha, LOCK_FILE_NAME);
+            config_dir.checkout(&lock.sha)?;
+            Ok(true)
+        }
+        Some(lock) if lock.configs == url => {
+            println!(
+                "Ignoring {} for {}, as the configs URL is for {}",
+                LOCK_FILE_NAME,
+                lock.branch.as_deref().unwrap_or("the default branch"),
+                branch.as_deref().unwrap_or_default()
+            );
+            Ok(false)
+        }
+        Some(lock) => {
+            println!("Ignoring {}, as it is for {}", LOCK_FILE_NAME, lock.configs);
+            Ok(false)
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hogan::auth::GitAuth;
+    use hogan::git::{CloneOptions, NativeGit};
+    use std::fs;
+
+    fn commit_configs(repo: &git2::Repository, data: &str) -> String {
+        let workdir = repo.workdir().unwrap();
+        fs::create_dir_all(workdir.join("configs")).unwrap();
+        fs::write(
+            workdir.join("configs/config.TEST.json"),
+            format!(
+                r#"{{"Environment": "TEST", "ConfigData": {{"Data": "{}"}}}}"#,
+                data
+            ),
+        )
+        .unwrap();
+        let mut index = repo.index().unwrap();
+        index
+            .add_path(Path::new("configs/config.TEST.json"))
+            .unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("hogan", "hogan@example.com").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            data,
+            &tree,
+            parent.iter().collect::<Vec<_>>().as_slice(),
+        )
+        .unwrap()
+        .to_string()
+    }
+
+    fn lock_at(url: &str, templates_path: &Path, update: bool) -> String {
+        let configs_url = url.parse::<ConfigUrl>().unwrap();
+        let mut config_dir = ConfigDir::new(
+            configs_url.clone(),
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
+        )
+        .unwrap();
+        lock_configs(&mut config_dir, &configs_url, templates_path, update).unwrap();
+        config_dir.head_sha().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_lock_configs() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let templates_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(source_dir.path()).unwrap();
+        let first = commit_configs(&repo, "first");
+        let url = format!(
+            "file://{}/configs",
+            source_dir.path().join(".git").display()
+        );
+
+        // The first run locks to the head
+        assert_eq!(lock_at(&url, templates_dir.path(), false), first);
+        let lock = Lock::load(templates_dir.path()).unwrap().unwrap();
+        assert_eq!((lock.sha.as_str(), lock.branch), (first.as_str(), None));
+
+        // Later runs check out the locked SHA rather than the head
+        let second = commit_configs(&repo, "second");
+        assert_eq!(lock_at(&url, templates_dir.path(), false), first);
+
+        // Updating locks to the head
+        assert_eq!(lock_at(&url, templates_dir.path(), true), second);
+        assert_eq!(lock_at(&url, templates_dir.path(), false), second);
+
+        // Changing the branch uses the head of the new branch, but keeps the lock
+        repo.branch(
+            "feature",
+            &repo
+                .find_commit(git2::Oid::from_str(&first).unwrap())
+                .unwrap(),
+            false,
+        )
+        .unwrap();
+        repo.set_head("refs/heads/feature").unwrap();
+        let feature = commit_configs(&repo, "feature");
+        let feature_url = format!("{}#feature", url);
+        assert_eq!(lock_at(&feature_url, templates_dir.path(), false), feature);
+        let lock = Lock::load(templates_dir.path()).unwrap().unwrap();
+        assert_eq!((lock.sha.as_str(), lock.branch), (second.as_str(), None));
+
+        // Until the lock is updated for it
+        assert_eq!(lock_at(&feature_url, templates_dir.path(), true), feature);
+        let lock = Lock::load(templates_dir.path()).unwrap().unwrap();
+        assert_eq!(lock.branch.as_deref(), Some("feature"));
+        commit_configs(&repo, "later");
+        assert_eq!(lock_at(&feature_url, templates_dir.path(), false), feature);
+
+        // A URL without a branch uses the lock from any branch
+        assert_eq!(lock_at(&url, templates_dir.path(), false), feature);
+    }
+}
@@ -1,6 +1,10 @@
 pub mod cli;
 pub mod config;
+pub mod coverage;
 pub mod datadogstatsd;
 mod fetch_actor;
 mod head_actor;
+pub mod lock;
+pub mod project;
 pub mod server;
+mod webhook;
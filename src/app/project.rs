@@ -0,0 +1,335 @@
+use crate::app::config::{App, AppCommand, AppCommon};
+use anyhow::{Context, Result};
+use hogan::config::ConfigUrl;
+use hogan::error::HoganError;
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::clap::ArgMatches;
+use url::Url;
+
+/// Project settings read from the root of a templates tree
+pub const PROJECT_FILE: &str = ".hogan.toml";
+
+/// Defaults for the transform and coverage flags, named after the flags they stand in for
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Project {
+    pub configs: Option<String>,
+    pub ssh_key: Option<String>,
+    pub environments_filter: Option<String>,
+    pub templates_filter: Option<String>,
+    pub strict: Option<bool>,
+    pub git: Option<bool>,
+    pub git_fetch: Option<bool>,
+    pub git_clone: Option<bool>,
+    pub gitignore: Option<bool>,
+}
+
+/// The arguments a project file can provide, borrowed from a parsed subcommand
+pub struct ProjectArgs<'a> {
+    pub common: &'a mut AppCommon,
+    pub templates_path: &'a mut PathBuf,
+    pub environments_regex: Option<&'a mut Regex>,
+    pub templates_regex: Option<&'a mut Regex>,
+    /// The --gitignore flag and whether --no-gitignore was given
+    pub gitignore: Option<(&'a mut bool, bool)>,
+}
+
+impl Project {
+    pub fn parse(src: &str) -> Result<Project> {
+        toml::from_str(src).map_err(|e| {
+            HoganError::InvalidConfiguration {
+                param: PROJECT_FILE.to_string(),
+                msg: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    /// Finds the project file in the templates directory or the closest of its parents,
+    /// returning the directory it was found in
+    pub fn discover(templates_path: &Path) -> Result<Option<(PathBuf, Project)>> {
+        let templates_path = templates_path
+            .canonicalize()
+            .with_context(|| format!("Unable to find the templates path {:?}", templates_path))?;
+
+        for dir in templates_path.ancestors() {
+            let path = dir.join(PROJECT_FILE);
+            if path.is_file() {
+                let project = Project::parse(&fs::read_to_string(&path)?)
+                    .with_context(|| format!("Error reading {:?}", path))?;
+                return Ok(Some((dir.to_path_buf(), project)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fills in the arguments which were not passed on the command line. Relative paths are
+    /// resolved against the directory of the project file
+    pub fn apply(&self, dir: &Path, matches: &ArgMatches, args: ProjectArgs) -> Result<()> {
+        let unset = |name: &str| matches.occurrences_of(name) == 0;
+
+        if unset("templates-path") {
+            *args.templates_path = dir.to_path_buf();
+        }
+        if let (Some(configs), true) = (&self.configs, args.common.configs_url.is_none()) {
+            let configs = if is_url(configs) {
+                configs.to_owned()
+            } else {
+                dir.join(configs).to_string_lossy().into_owned()
+            };
+            args.common.configs_url = Some(configs.parse::<ConfigUrl>()?);
+        }
+        if let (Some(ssh_key), true) = (&self.ssh_key, unset("ssh-key")) {
+            args.common.ssh_key = dir.join(App::parse_path_buf(ssh_key));
+        }
+        if let (Some(filter), Some(regex), true) = (
+            &self.environments_filter,
+            args.environments_regex,
+            unset("environments-regex"),
+        ) {
+            *regex = App::parse_regex(filter)?;
+        }
+        if let (Some(filter), Some(regex), true) = (
+            &self.templates_filter,
+            args.templates_regex,
+            unset("templates-regex"),
+        ) {
+            *regex = App::parse_regex(filter)?;
+        }
+
+        // A flag or its --no- form on the command line wins over the project file
+        let flag = |value: &mut bool, name: &str, off: bool, setting: Option<bool>| {
+            if let (Some(setting), true) = (setting, unset(name) && !off) {
+                *value = setting;
+            }
+        };
+        let common = args.common;
+        flag(&mut common.strict, "strict", common.no_strict, self.strict);
+        flag(
+            &mut common.native_git,
+            "native-git",
+            common.no_native_git,
+            self.git,
+        );
+        flag(
+            &mut common.native_fetch,
+            "native-fetch",
+            common.no_native_fetch,
+            self.git_fetch,
+        );
+        flag(
+            &mut common.native_clone,
+            "native-clone",
+            common.no_native_clone,
+            self.git_clone,
+        );
+        if let Some((gitignore, no_gitignore)) = args.gitignore {
+            flag(gitignore, "gitignore", no_gitignore, self.gitignore);
+        }
+
+        Ok(())
+    }
+}
+
+// Urls, including scp-style git urls such as git@host:org/configs.git, are used as they are
+fn is_url(configs: &str) -> bool {
+    Url::parse(configs).is_ok()
+        || configs
+            .split_once(':')
+            .is_some_and(|(host, _)| !host.is_empty() && !host.contains('/'))
+}
+
+/// Applies the project file found from the templates directory of a transform, coverage or lock
+/// command to the arguments it was not given
+pub fn apply_project(app: &mut App, matches: &ArgMatches) -> Result<()> {
+    let matches = match matches.subcommand() {
+        (_, Some(matches)) => matches,
+        _ => return Ok(()),
+    };
+    let args = match app.cmd {
+        AppCommand::Transform {
+            ref mut common,
+            ref mut templates_path,
+            ref mut environments_regex,
+            ref mut templates_regex,
+            ref mut gitignore,
+            no_gitignore,
+            ..
+        }
+        | AppCommand::Coverage {
+            ref mut common,
+            ref mut templates_path,
+            ref mut environments_regex,
+            ref mut templates_regex,
+            ref mut gitignore,
+            no_gitignore,
+            ..
+        } => ProjectArgs {
+            common,
+            templates_path,
+            environments_regex: Some(environments_regex),
+            templates_regex: Some(templates_regex),
+            gitignore: Some((gitignore, no_gitignore)),
+        },
+        AppCommand::Lock {
+            ref mut common,
+            ref mut templates_path,
+            ..
+        } => ProjectArgs {
+            common,
+            templates_path,
+            environments_regex: None,
+            templates_regex: None,
+            gitignore: None,
+        },
+        AppCommand::Server { .. } => return Ok(()),
+    };
+
+    if let Some((dir, project)) = Project::discover(args.templates_path)? {
+        info!("Using project settings from {:?}", dir.join(PROJECT_FILE));
+        project.apply(&dir, matches, args)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_parse_project() {
+        let project = Project::parse(
+            r#"
+            configs = "../configs"
+            environments-filter = "TEST.*"
+            strict = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            project,
+            Project {
+                configs: Some("../configs".to_string()),
+                environments_filter: Some("TEST.*".to_string()),
+                strict: Some(true),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_command_line_wins() {
+        let project = Project {
+            strict: Some(true),
+            git: Some(false),
+            gitignore: Some(true),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let apply = |args: &[&str]| {
+            let matches = App::clap()
+                .get_matches_from_safe(["hogan", "transform", "-c", "configs"].iter().chain(args));
+            let matches = matches.unwrap();
+            let mut app = App::from_clap(&matches);
+            let (_, matches) = matches.subcommand();
+            match app.cmd {
+                AppCommand::Transform {
+                    ref mut common,
+                    ref mut templates_path,
+                    ref mut gitignore,
+                    no_gitignore,
+                    ..
+                } => {
+                    project
+                        .apply(
+                            dir.path(),
+                            matches.unwrap(),
+                            ProjectArgs {
+                                common,
+                                templates_path,
+                                environments_regex: None,
+                                templates_regex: None,
+                                gitignore: Some((gitignore, no_gitignore)),
+                            },
+                        )
+                        .unwrap();
+                    (common.strict, common.native_git, *gitignore)
+                }
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(apply(&[]), (true, false, true));
+        assert_eq!(apply(&["--no-strict", "--git"]), (false, true, true));
+        assert_eq!(apply(&["--no-gitignore"]), (true, false, false));
+        assert_eq!(apply(&["--strict", "--no-strict"]), (false, false, true));
+    }
+
+    #[test]
+    fn test_configs_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let configs = |configs: &str| {
+            let project = Project {
+                configs: Some(configs.to_string()),
+                ..Default::default()
+            };
+            let matches = App::clap()
+                .get_matches_from_safe(["hogan", "transform"])
+                .unwrap();
+            let mut app = App::from_clap(&matches);
+            let (_, matches) = matches.subcommand();
+            match app.cmd {
+                AppCommand::Transform {
+                    ref mut common,
+                    ref mut templates_path,
+                    ..
+                } => {
+                    project
+                        .apply(
+                            dir.path(),
+                            matches.unwrap(),
+                            ProjectArgs {
+                                common,
+                                templates_path,
+                                environments_regex: None,
+                                templates_regex: None,
+                                gitignore: None,
+                            },
+                        )
+                        .unwrap();
+                    common.configs_url.clone().unwrap()
+                }
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(
+            configs("git@github.com:org/configs.git"),
+            "ssh://git@github.com/org/configs.git".parse().unwrap()
+        );
+        assert_eq!(
+            configs("https://github.com/org/configs.git#main"),
+            ConfigUrl::Git {
+                url: Url::parse("https://github.com/org/configs.git").unwrap(),
+                branch: Some("main".to_string()),
+                internal_path: PathBuf::new(),
+            }
+        );
+        assert_eq!(
+            configs("../configs"),
+            ConfigUrl::File {
+                path: dir.path().join("../configs"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_project_unknown_key() {
+        assert!(Project::parse("config = \"../configs\"").is_err());
+    }
+}
@@ -1,23 +1,32 @@
 use crate::app::config::AppCommon;
 use crate::app::datadogstatsd::{CustomMetrics, DdMetrics};
-use crate::app::fetch_actor;
-use crate::app::head_actor;
+use crate::app::fetch_actor::{self, FetchHealth, FetchSchedule};
+use crate::app::head_actor::{self, HeadCache};
+use crate::app::webhook::{self, HookEvent};
 use crate::storage::cache::Cache;
 use crate::storage::cache::CleanupActor;
 use crate::storage::lru::LruEnvCache;
 use crate::storage::sqlite::SqliteCache;
 use actix_web::dev::Service;
 use actix_web::middleware::Logger;
-use actix_web::{get, middleware, post, web, HttpResponse, HttpServer};
+use actix_web::{get, middleware, post, web, HttpRequest, HttpResponse, HttpServer};
 use anyhow::{Context, Result};
-use hogan::config::ConfigDir;
+use handlebars::Handlebars;
+use hogan::config::{ConfigDir, ConfigUrl};
 use hogan::error::HoganError;
+use hogan::git::{is_object_id, GitTimer, NativeGit};
+use hogan::signature::AllowedSigners;
+use hogan::template::{CompiledTemplate, Metadata};
+use lru::LruCache;
 use parking_lot::Mutex;
 use regex::Regex;
-use riker::actors::ActorSystem;
+use riker::actors::{ActorRef, ActorSystem};
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::SystemTime;
@@ -26,13 +35,21 @@ struct ServerState {
     config_dir: Arc<hogan::config::ConfigDir>,
     write_lock: Mutex<usize>,
     environments_regex: Regex,
-    strict: bool,
+    handlebars: Handlebars<'static>,
+    template_cache: Mutex<LruCache<u64, Arc<(String, CompiledTemplate)>>>,
     allow_fetch: bool,
+    tree_reads: bool,
+    allowed_signers: Option<AllowedSigners>,
+    // Full ids of commits recently found to be signed, so each is only verified once
+    verified_shas: Mutex<LruCache<String, ()>>,
     dd_metrics: Arc<DdMetrics>,
     environment_pattern: String,
     caches: Vec<Arc<Box<dyn Cache + Sync + Send>>>,
     actor_system: ActorSystem,
     head_request_actor: head_actor::HeadRequestActor,
+    webhook_secret: Option<String>,
+    fetch_actor: ActorRef<fetch_actor::FetchActorMsg>,
+    fetch_health: Arc<Mutex<FetchHealth>>,
 }
 
 fn response_map<'a>() -> HashMap<&'a str, &'a str> {
@@ -74,6 +91,12 @@ fn create_error_response(e: anyhow::Error) -> HttpResponse {
             body.insert("message", "Unknown Environment");
             HttpResponse::NotFound().json(body)
         }
+        HoganError::UntrustedCommit { sha, msg } => {
+            let mut body = response_map();
+            body.insert("sha", &sha);
+            body.insert("message", &msg);
+            HttpResponse::Forbidden().json(body)
+        }
         HoganError::InternalTimeout => {
             error!("Internal Timeout Occurred {:?}", he);
             HttpResponse::ServiceUnavailable().finish()
@@ -96,9 +119,26 @@ pub fn start_up_server(
     db_path: String,
     cache_size: usize,
     fetch_poller: u64,
+    fetch_max_backoff: u64,
+    maintenance_interval: u64,
     allow_fetch: bool,
+    tree_reads: bool,
+    fallback_remotes: Vec<ConfigUrl>,
+    webhook_secret: Option<String>,
+    webhook_debounce: u64,
+    head_timeout: u64,
+    head_cache_ttl: u64,
     db_max_age: usize,
 ) -> Result<()> {
+    // Anyone could sign a webhook with an empty secret
+    if webhook_secret.as_deref().map(str::trim) == Some("") {
+        return Err(HoganError::InvalidConfiguration {
+            param: "webhook-secret".to_string(),
+            msg: "The webhook secret must not be empty".to_string(),
+        }
+        .into());
+    }
+
     let caches: Vec<Arc<Box<dyn Cache + Send + Sync>>> = vec![
         Arc::new(Box::new(LruEnvCache::new("lru", cache_size)?)),
         Arc::new(Box::new(SqliteCache::new(&db_path))),
@@ -112,28 +152,70 @@ pub fn start_up_server(
 
     info!("datadog monitoring is setting: {}", datadog);
     let dd_metrics = Arc::new(DdMetrics::new(datadog));
-    let config_dir = Arc::new(ConfigDir::new(
-        common.configs_url,
-        &common.ssh_key,
+    let allowed_signers = common.allowed_signers();
+    let mut config_dir = ConfigDir::new(
+        common.configs_url()?,
+        &common.git_auth()?,
         common.native_git,
         common.native_fetch,
         common.native_clone,
-    )?);
+        NativeGit {
+            timer: Some(native_git_timer(dd_metrics.clone())),
+            ..common.git_runner()
+        },
+        common.clone_options(),
+        None,
+    )?;
+    for fallback in fallback_remotes {
+        match fallback {
+            ConfigUrl::Git { url, .. } => config_dir.add_fallback_remote(&url)?,
+            ConfigUrl::File { path } => {
+                return Err(HoganError::InvalidConfiguration {
+                    param: "fallback-remote".to_string(),
+                    msg: format!("{:?} is not a git repository", path),
+                }
+                .into())
+            }
+        };
+    }
+    let head_cache = Arc::new(HeadCache::new(Duration::from_millis(head_cache_ttl)));
+    head_cache.check_refs(config_dir.ref_targets().ok());
+    let fetch_health = Arc::new(Mutex::new(FetchHealth::default()));
+    config_dir.set_fetch_hooks(fetch_actor::fetch_hooks(
+        fetch_health.clone(),
+        dd_metrics.clone(),
+        head_cache.clone(),
+    ));
+    let config_dir = Arc::new(config_dir);
 
     let actor_system = ActorSystem::new()?;
-    let head_request_actor =
-        head_actor::init_system(&actor_system, config_dir.clone(), allow_fetch);
+    let head_request_actor = head_actor::init_system(
+        &actor_system,
+        config_dir.clone(),
+        allow_fetch,
+        Duration::from_secs(head_timeout),
+        head_cache.clone(),
+        dd_metrics.clone(),
+    );
 
-    fetch_actor::init_system(
+    let fetch_actor = fetch_actor::init_system(
         &actor_system,
         config_dir.clone(),
         dd_metrics.clone(),
-        fetch_poller,
+        FetchSchedule {
+            poll: Duration::from_millis(fetch_poller),
+            debounce: Duration::from_millis(webhook_debounce),
+            max_backoff: Duration::from_secs(fetch_max_backoff),
+            maintenance_interval: Duration::from_secs(maintenance_interval),
+        },
+        fetch_health.clone(),
     );
 
     CleanupActor::init_db_cleanup_system(&actor_system, &caches, db_max_age, dd_metrics.clone());
 
     let write_lock = Mutex::new(0);
+    let cache_capacity = NonZeroUsize::new(cache_size).context("Cache size must be positive")?;
+    let template_cache = Mutex::new(LruCache::new(cache_capacity));
 
     info!("Starting server on {}:{}", address, port);
 
@@ -141,19 +223,41 @@ pub fn start_up_server(
         config_dir,
         write_lock,
         environments_regex,
-        strict: common.strict,
+        handlebars: hogan::transform::handlebars(common.strict),
+        template_cache,
         dd_metrics,
         environment_pattern,
         caches,
         actor_system,
         head_request_actor,
         allow_fetch,
+        tree_reads,
+        allowed_signers,
+        verified_shas: Mutex::new(LruCache::new(cache_capacity)),
+        webhook_secret,
+        fetch_actor,
+        fetch_health,
     };
     start_server(address, port, state)?;
 
     Ok(())
 }
 
+// Reports how long each native git command took, tagged with its subcommand and outcome
+fn native_git_timer(dd_metrics: Arc<DdMetrics>) -> GitTimer {
+    Arc::new(move |command, elapsed, success| {
+        let tags = vec![
+            format!("command:{}", command),
+            format!("result:{}", if success { "ok" } else { "error" }),
+        ];
+        dd_metrics.time(
+            CustomMetrics::NativeGitTime.into(),
+            Some(tags),
+            elapsed.as_millis() as i64,
+        );
+    })
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "PascalCase")]
 struct EnvDescription {
@@ -246,8 +350,12 @@ async fn start_server(address: String, port: u16, state: ServerState) -> std::io
             .service(get_envs)
             .service(get_config_by_env)
             .service(get_config_by_env_branch)
+            .service(get_config_by_env_tag)
+            .service(transform_tag)
             .service(get_branch_sha)
+            .service(git_hook)
             .service(ok_route)
+            .service(health_route)
     })
     .bind(binding)?
     .run()
@@ -259,6 +367,42 @@ async fn ok_route() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
+// Always succeeds, so a git server outage does not take the server out of rotation while it can still
+// serve the SHAs it has
+#[get("health")]
+async fn health_route(state: web::Data<ServerState>) -> HttpResponse {
+    let health = state.fetch_health.lock().clone();
+    HttpResponse::Ok().json(health)
+}
+
+#[post("hooks/git")]
+async fn git_hook(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<ServerState>,
+) -> HttpResponse {
+    let secret = match &state.webhook_secret {
+        Some(secret) => secret,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    if !webhook::verify(secret, req.headers(), &body) {
+        warn!("Rejected a git webhook without a valid signature");
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match webhook::parse_event(req.headers(), &body) {
+        HookEvent::Push(refs) => {
+            info!("Fetching after a push to {:?}", refs);
+            fetch_actor::request_fetch(&state.fetch_actor);
+            HttpResponse::Accepted().finish()
+        }
+        HookEvent::Other(event) => {
+            debug!("Ignoring {} webhook", event);
+            HttpResponse::Ok().finish()
+        }
+    }
+}
+
 #[derive(Deserialize, Clone)]
 struct TransformEnvParams {
     sha: String,
@@ -312,16 +456,57 @@ fn transform_from_sha(
 ) -> Result<String> {
     let env = get_env(state, None, sha, env_name)?;
 
-    let handlebars = hogan::transform::handlebars(state.strict);
-    handlebars
-        .render_template(&data, &env.config_data)
-        .map_err(|e| {
-            HoganError::InvalidTemplate {
-                msg: format!("Template Error {:?}", e),
-                env: env_name.to_string(),
-            }
-            .into()
-        })
+    let invalid_template = |msg| -> anyhow::Error {
+        HoganError::InvalidTemplate {
+            msg,
+            env: env_name.to_string(),
+        }
+        .into()
+    };
+
+    let template = compile_template(state, data)
+        .map_err(|e| invalid_template(format!("Template Error {:?}", e)))?;
+    let mut rendered = Vec::new();
+    let files = template
+        .1
+        .render(
+            &state.handlebars,
+            &env.config_data,
+            &Metadata::new(&env, Some(sha)),
+            &mut rendered,
+        )
+        .map_err(|e| invalid_template(format!("Template Error {:?}", e)))?;
+    // A transform responds with a single body, which has no room for files emitted with hogan-file
+    if !files.is_empty() {
+        return Err(invalid_template(format!(
+            "Template Error: {} file(s) were emitted with hogan-file, which the server can not return",
+            files.len()
+        )));
+    }
+    String::from_utf8(rendered).map_err(|e| invalid_template(format!("Template Error {:?}", e)))
+}
+
+// Templates are cached by a hash of their source so repeated requests skip parsing
+fn compile_template(
+    state: &ServerState,
+    data: String,
+) -> Result<Arc<(String, CompiledTemplate)>, Box<handlebars::TemplateError>> {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    let key = hasher.finish();
+
+    if let Some(template) = state.template_cache.lock().get(&key) {
+        if template.0 == data {
+            register_template_cache(state, "hit");
+            return Ok(template.clone());
+        }
+    }
+    register_template_cache(state, "miss");
+
+    let compiled = CompiledTemplate::compile(&data)?;
+    let template = Arc::new((data, compiled));
+    state.template_cache.lock().put(key, template.clone());
+    Ok(template)
 }
 
 #[derive(Deserialize)]
@@ -406,6 +591,77 @@ async fn get_config_by_env_branch(
     }
 }
 
+#[derive(Deserialize)]
+struct ConfigByEnvTagState {
+    tag: String,
+    env: String,
+}
+
+#[get("tags/{tag:.*}/configs/{env}")]
+async fn get_config_by_env_tag(
+    params: web::Path<ConfigByEnvTagState>,
+    state: web::Data<ServerState>,
+) -> HttpResponse {
+    let result = match web::block(move || {
+        let sha = find_branch_head(&tag_ref(&params.tag), &state)?;
+        get_env(&state, None, &sha, &params.env)
+    })
+    .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Error joining when querying configs by tag");
+            Err(e.into())
+        }
+    };
+
+    match result {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => create_error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct TagTransformParams {
+    tag: String,
+    environment: String,
+}
+
+#[post("tags/{tag:.*}/transform/{environment}")]
+async fn transform_tag(
+    data: String,
+    params: web::Path<TagTransformParams>,
+    state: web::Data<ServerState>,
+) -> HttpResponse {
+    let tag = params.tag.to_owned();
+    let environment = params.environment.to_owned();
+    let result = match web::block(move || {
+        let sha = find_branch_head(&tag_ref(&params.tag), &state)?;
+        transform_from_sha(data, &sha, &params.environment, &state)
+    })
+    .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            warn!(
+                "Error joining from a template request {} {} {:?}",
+                environment, tag, e
+            );
+            Err(e.into())
+        }
+    };
+
+    match result {
+        Ok(result) => HttpResponse::Ok().body(result),
+        Err(e) => create_error_response(e),
+    }
+}
+
+// Tags are looked up by their full ref, so a branch with the same name is never used instead
+fn tag_ref(tag: &str) -> String {
+    format!("refs/tags/{}", tag)
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ShaResponse {
@@ -500,6 +756,13 @@ fn register_cache_hit(state: &ServerState) {
     );
 }
 
+fn register_template_cache(state: &ServerState, action: &str) {
+    state.dd_metrics.incr(
+        CustomMetrics::TemplateCache.into(),
+        Some(vec![format!("action:{}", action)]),
+    );
+}
+
 fn register_cache_miss(state: &ServerState) {
     state.dd_metrics.incr(
         CustomMetrics::Cache.into(),
@@ -547,6 +810,7 @@ fn get_env(
     sha: &str,
     env: &str,
 ) -> Result<Arc<hogan::config::Environment>> {
+    verify_commit(state, remote, sha)?;
     let caches = &state.caches;
 
     //Check caches before git repo
@@ -555,30 +819,6 @@ fn get_env(
         return Ok(environment);
     }
 
-    // This locks mutating the git repo
-    let _write_lock = match state.write_lock.try_lock_for(Duration::from_secs(20)) {
-        Some(lock) => lock,
-        None => {
-            warn!(
-                "Unable to acquire write lock in a timely manner: {} {}",
-                sha, env
-            );
-            return Err(HoganError::InternalTimeout.into());
-        }
-    };
-
-    //Since waiting for the lock may take unknown time double check if the cache now contains the env we are looking for
-    if let Some(environment) = check_caches_env(caches, env, sha) {
-        register_cache_hit(state);
-        return Ok(environment);
-    }
-
-    register_cache_miss(state);
-
-    let sha = state
-        .config_dir
-        .refresh(remote, Some(sha), state.allow_fetch)?;
-
     let filter = match hogan::config::build_env_regex(env, Some(&state.environment_pattern)) {
         Ok(filter) => filter,
         Err(e) => {
@@ -587,12 +827,38 @@ fn get_env(
             state.environments_regex.clone()
         }
     };
-    if let Some(environment) = state
-        .config_dir
-        .find(filter)
-        .iter()
-        .find(|e| e.environment == env)
-    {
+
+    let (sha, environments) = if state.tree_reads {
+        register_cache_miss(state);
+        find_at(state, remote, sha, filter)?
+    } else {
+        // This locks mutating the git repo
+        let _write_lock = match state.write_lock.try_lock_for(Duration::from_secs(20)) {
+            Some(lock) => lock,
+            None => {
+                warn!(
+                    "Unable to acquire write lock in a timely manner: {} {}",
+                    sha, env
+                );
+                return Err(HoganError::InternalTimeout.into());
+            }
+        };
+
+        //Since waiting for the lock may take unknown time double check if the cache now contains the env we are looking for
+        if let Some(environment) = check_caches_env(caches, env, sha) {
+            register_cache_hit(state);
+            return Ok(environment);
+        }
+
+        register_cache_miss(state);
+
+        let sha = state
+            .config_dir
+            .refresh(remote, Some(sha), state.allow_fetch)?;
+        (sha, state.config_dir.find(filter))
+    };
+
+    if let Some(environment) = environments.iter().find(|e| e.environment == env) {
         for cache in caches {
             if let Err(e) = cache.write_env(env, &sha, environment) {
                 error!(
@@ -647,6 +913,7 @@ fn get_env_listing(
     remote: Option<&str>,
     sha: &str,
 ) -> Result<Arc<Vec<EnvDescription>>> {
+    verify_commit(state, remote, sha)?;
     let caches = &state.caches;
 
     if let Some(env) = check_caches_env_listing(caches, sha) {
@@ -654,29 +921,36 @@ fn get_env_listing(
         return Ok(Arc::new(env.iter().map(|x| x.into()).collect()));
     }
 
-    let _write_lock = match state.write_lock.try_lock_for(Duration::from_secs(20)) {
-        Some(l) => l,
-        None => {
-            log::warn!(
-                "Unable to acquire write lock for env listing in a timely manner {}",
-                sha
-            );
-            return Err(HoganError::InternalTimeout.into());
-        }
-    };
+    let (sha, environments) = if state.tree_reads {
+        register_cache_miss(state);
+        find_at(state, remote, sha, state.environments_regex.clone())?
+    } else {
+        let _write_lock = match state.write_lock.try_lock_for(Duration::from_secs(20)) {
+            Some(l) => l,
+            None => {
+                log::warn!(
+                    "Unable to acquire write lock for env listing in a timely manner {}",
+                    sha
+                );
+                return Err(HoganError::InternalTimeout.into());
+            }
+        };
 
-    if let Some(env) = check_caches_env_listing(caches, sha) {
-        register_cache_hit(state);
-        return Ok(Arc::new(env.iter().map(|x| x.into()).collect()));
-    }
+        if let Some(env) = check_caches_env_listing(caches, sha) {
+            register_cache_hit(state);
+            return Ok(Arc::new(env.iter().map(|x| x.into()).collect()));
+        }
 
-    register_cache_miss(state);
+        register_cache_miss(state);
 
-    let sha = state
-        .config_dir
-        .refresh(remote, Some(sha), state.allow_fetch)?;
+        let sha = state
+            .config_dir
+            .refresh(remote, Some(sha), state.allow_fetch)?;
+        let environments = state.config_dir.find(state.environments_regex.clone());
+        (sha, environments)
+    };
 
-    let envs = format_envs(&state.config_dir.find(state.environments_regex.clone()));
+    let envs = format_envs(&environments);
     let to_cache_envs = envs
         .iter()
         .map(|x| x.into())
@@ -696,6 +970,82 @@ fn get_env_listing(
     Ok(Arc::new(envs))
 }
 
+/// Reads environments straight from the git tree of a SHA, so requests for different SHAs don't
+/// wait on each other. Only fetching an unknown SHA takes the write lock
+fn find_at(
+    state: &ServerState,
+    remote: Option<&str>,
+    sha: &str,
+    filter: Regex,
+) -> Result<(String, Vec<hogan::config::Environment>)> {
+    match state.config_dir.find_at(remote, sha, filter.clone(), false) {
+        Err(e)
+            if state.allow_fetch
+                && matches!(e.downcast_ref(), Some(HoganError::UnknownSHA { .. })) =>
+        {
+            let _write_lock = match state.write_lock.try_lock_for(Duration::from_secs(20)) {
+                Some(lock) => lock,
+                None => {
+                    warn!(
+                        "Unable to acquire write lock to fetch in a timely manner {}",
+                        sha
+                    );
+                    return Err(HoganError::InternalTimeout.into());
+                }
+            };
+            state.config_dir.find_at(remote, sha, filter, true)
+        }
+        result => result,
+    }
+}
+
+/// Rejects commits without a signature from an allowed signer, before anything cached for them is
+/// served. Unknown commits are fetched when fetching is allowed
+fn verify_commit(state: &ServerState, remote: Option<&str>, sha: &str) -> Result<()> {
+    let signers = match &state.allowed_signers {
+        Some(signers) => signers,
+        None => return Ok(()),
+    };
+    // A ref could later move to a commit which was never verified
+    if !is_object_id(sha) {
+        return Err(HoganError::UnknownSHA {
+            sha: sha.to_owned(),
+        })
+        .context("Only SHAs can be requested when commits must be signed");
+    }
+
+    let commit_id = match state.config_dir.commit_id(sha) {
+        Err(e)
+            if state.allow_fetch
+                && matches!(e.downcast_ref(), Some(HoganError::UnknownSHA { .. })) =>
+        {
+            let _write_lock = match state.write_lock.try_lock_for(Duration::from_secs(20)) {
+                Some(lock) => lock,
+                None => {
+                    warn!(
+                        "Unable to acquire write lock to fetch in a timely manner {}",
+                        sha
+                    );
+                    return Err(HoganError::InternalTimeout.into());
+                }
+            };
+            info!("Couldn't find {}. Trying to refreshing repo", sha);
+            state
+                .config_dir
+                .fetch_with_fallback(remote.unwrap_or("origin"))?;
+            state.config_dir.commit_id(sha)?
+        }
+        result => result?,
+    };
+    if state.verified_shas.lock().get(&commit_id).is_some() {
+        return Ok(());
+    }
+
+    state.config_dir.verify_commit(&commit_id, signers)?;
+    state.verified_shas.lock().put(commit_id, ());
+    Ok(())
+}
+
 fn format_envs(envs: &[hogan::config::Environment]) -> Vec<EnvDescription> {
     envs.iter().map(|e| e.into()).collect()
 }
@@ -707,3 +1057,148 @@ fn format_sha(sha: &str) -> &str {
         sha
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use hogan::auth::GitAuth;
+    use hogan::config::build_regex;
+    use hogan::git::CloneOptions;
+    use std::path::{Path, PathBuf};
+
+    fn commit_config(repo: &git2::Repository, version: u32) -> String {
+        let workdir = repo.workdir().unwrap();
+        std::fs::create_dir_all(workdir.join("configs")).unwrap();
+        std::fs::write(
+            workdir.join("configs/config.TEST.json"),
+            format!(
+                r#"{{"Environment": "TEST", "ConfigData": {{"Version": {}}}}}"#,
+                version
+            ),
+        )
+        .unwrap();
+        let mut index = repo.index().unwrap();
+        index
+            .add_path(Path::new("configs/config.TEST.json"))
+            .unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("hogan", "hogan@example.com").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Update configs",
+            &tree,
+            parent.iter().collect::<Vec<_>>().as_slice(),
+        )
+        .unwrap()
+        .to_string()
+    }
+
+    fn server_state(config_dir: ConfigDir, tree_reads: bool) -> ServerState {
+        let config_dir = Arc::new(config_dir);
+        let dd_metrics = Arc::new(DdMetrics::new(false));
+        let actor_system = ActorSystem::new().unwrap();
+        let head_request_actor = head_actor::init_system(
+            &actor_system,
+            config_dir.clone(),
+            false,
+            Duration::from_secs(5),
+            Arc::new(HeadCache::new(Duration::ZERO)),
+            dd_metrics.clone(),
+        );
+        let fetch_health = Arc::new(Mutex::new(FetchHealth::default()));
+        let fetch_actor = fetch_actor::init_system(
+            &actor_system,
+            config_dir.clone(),
+            dd_metrics.clone(),
+            FetchSchedule {
+                poll: Duration::from_secs(3600),
+                debounce: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(3600),
+                maintenance_interval: Duration::from_secs(3600),
+            },
+            fetch_health.clone(),
+        );
+        let capacity = NonZeroUsize::new(8).unwrap();
+        ServerState {
+            config_dir,
+            write_lock: Mutex::new(0),
+            environments_regex: build_regex(r"config\..+\.json$").unwrap(),
+            handlebars: hogan::transform::handlebars(false),
+            template_cache: Mutex::new(LruCache::new(capacity)),
+            allow_fetch: false,
+            tree_reads,
+            allowed_signers: None,
+            verified_shas: Mutex::new(LruCache::new(capacity)),
+            dd_metrics,
+            environment_pattern: r"^config\.{}\.json$".to_string(),
+            caches: vec![Arc::new(Box::new(LruEnvCache::new("lru", 8).unwrap()))],
+            actor_system,
+            head_request_actor,
+            webhook_secret: None,
+            fetch_actor,
+            fetch_health,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_tree_reads() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(source_dir.path()).unwrap();
+        let first = commit_config(&repo, 1);
+        let second = commit_config(&repo, 2);
+        let config_dir = ConfigDir::new(
+            ConfigUrl::Git {
+                url: url::Url::from_file_path(source_dir.path()).unwrap(),
+                branch: None,
+                internal_path: PathBuf::from("configs"),
+            },
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
+        )
+        .unwrap();
+
+        let state = web::Data::new(server_state(config_dir, true));
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(state.clone())
+                .service(get_envs)
+                .service(get_config_by_env),
+        )
+        .await;
+
+        // An older SHA is read from its tree, leaving the checkout at the head
+        let request = test::TestRequest::get()
+            .uri(&format!("/configs/{}/TEST", first))
+            .to_request();
+        let environment: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(environment["ConfigData"]["Version"], 1);
+        assert_eq!(state.config_dir.head_sha(), Some(second.as_str()));
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/envs/{}", &first[..7]))
+            .to_request();
+        let environments: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(
+            environments,
+            serde_json::json!([{"Name": "TEST", "Type": null}])
+        );
+
+        let request = test::TestRequest::get()
+            .uri("/configs/0000000000000000000000000000000000000000/TEST")
+            .to_request();
+        assert!(test::call_service(&app, request)
+            .await
+            .status()
+            .is_client_error());
+    }
+}
@@ -0,0 +1,209 @@
+use actix_web::http::header::HeaderMap;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha1::Sha1;
+use sha2::Sha256;
+
+/// An event a git host sent to the webhook
+#[derive(Debug, PartialEq, Eq)]
+pub enum HookEvent {
+    /// Refs were pushed. The names of the refs are listed when the payload has them
+    Push(Vec<String>),
+    /// Any other event, such as the ping sent when a hook is created
+    Other(String),
+}
+
+/// Checks that a request was sent by a host which knows the shared secret. GitHub and Bitbucket
+/// sign the body with an HMAC in `X-Hub-Signature-256` or `X-Hub-Signature`, while GitLab sends the
+/// secret itself in `X-Gitlab-Token`
+pub fn verify(secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    if let Some(signature) = header(headers, "x-hub-signature-256") {
+        return match signature.strip_prefix("sha256=") {
+            Some(signature) => hmac_matches::<Hmac<Sha256>>(secret, body, signature),
+            None => false,
+        };
+    }
+    if let Some(signature) = header(headers, "x-hub-signature") {
+        return match signature.split_once('=') {
+            Some(("sha256", signature)) => hmac_matches::<Hmac<Sha256>>(secret, body, signature),
+            Some(("sha1", signature)) => hmac_matches::<Hmac<Sha1>>(secret, body, signature),
+            _ => false,
+        };
+    }
+    match header(headers, "x-gitlab-token") {
+        Some(token) => constant_time_eq(token.as_bytes(), secret.as_bytes()),
+        None => false,
+    }
+}
+
+/// Reads the kind of event from the headers of each host, and the pushed refs from its payload.
+/// Requests from other senders are taken to be pushes
+pub fn parse_event(headers: &HeaderMap, body: &[u8]) -> HookEvent {
+    let event = header(headers, "x-github-event")
+        .or_else(|| header(headers, "x-gitlab-event"))
+        .or_else(|| header(headers, "x-event-key"));
+    match event {
+        None | Some("push" | "Push Hook" | "Tag Push Hook" | "repo:push" | "repo:refs_changed") => {
+            let payload = serde_json::from_slice(body).unwrap_or(Value::Null);
+            HookEvent::Push(pushed_refs(&payload))
+        }
+        Some(event) => HookEvent::Other(event.to_owned()),
+    }
+}
+
+fn pushed_refs(payload: &Value) -> Vec<String> {
+    // GitHub and GitLab
+    if let Some(reference) = payload["ref"].as_str() {
+        return vec![reference.to_owned()];
+    }
+    // Bitbucket Cloud, where deleted refs have no new name
+    if let Some(changes) = payload["push"]["changes"].as_array() {
+        return changes
+            .iter()
+            .filter_map(|change| change["new"]["name"].as_str())
+            .map(|name| name.to_owned())
+            .collect();
+    }
+    // Bitbucket Server
+    payload["changes"]
+        .as_array()
+        .map(|changes| {
+            changes
+                .iter()
+                .filter_map(|change| change["refId"].as_str())
+                .map(|reference| reference.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn hmac_matches<M: Mac + hmac::digest::KeyInit>(
+    secret: &str,
+    body: &[u8],
+    signature: &str,
+) -> bool {
+    let mut mac = <M as Mac>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let expected = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    constant_time_eq(
+        expected.as_bytes(),
+        signature.to_ascii_lowercase().as_bytes(),
+    )
+}
+
+// Compares every byte, so the time taken does not reveal how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn test_verify() {
+        let body = br#"{"ref": "refs/heads/master"}"#;
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body);
+        let valid = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        let github = |signature: &str| {
+            verify(
+                "secret",
+                &headers(&[("x-hub-signature-256", &format!("sha256={}", signature))]),
+                body,
+            )
+        };
+        assert!(github(&valid));
+        assert!(github(&valid.to_uppercase()));
+        assert!(!github(&"0".repeat(64)));
+        assert!(!verify(
+            "other",
+            &headers(&[("x-hub-signature-256", &format!("sha256={}", valid))]),
+            body
+        ));
+        assert!(verify(
+            "secret",
+            &headers(&[("x-hub-signature", &format!("sha256={}", valid))]),
+            body
+        ));
+
+        assert!(verify(
+            "secret",
+            &headers(&[("x-gitlab-token", "secret")]),
+            body
+        ));
+        assert!(!verify(
+            "secret",
+            &headers(&[("x-gitlab-token", "secre")]),
+            body
+        ));
+        assert!(!verify("secret", &HeaderMap::new(), body));
+    }
+
+    #[test]
+    fn test_parse_event() {
+        assert_eq!(
+            parse_event(
+                &headers(&[("x-github-event", "push")]),
+                br#"{"ref": "refs/tags/v1"}"#
+            ),
+            HookEvent::Push(vec!["refs/tags/v1".to_string()])
+        );
+        assert_eq!(
+            parse_event(&headers(&[("x-github-event", "ping")]), b"{}"),
+            HookEvent::Other("ping".to_string())
+        );
+        assert_eq!(
+            parse_event(
+                &headers(&[("x-gitlab-event", "Tag Push Hook")]),
+                br#"{"ref": "refs/tags/v2"}"#
+            ),
+            HookEvent::Push(vec!["refs/tags/v2".to_string()])
+        );
+        assert_eq!(
+            parse_event(
+                &headers(&[("x-event-key", "repo:push")]),
+                br#"{"push": {"changes": [{"new": {"name": "master"}}, {"new": null}]}}"#
+            ),
+            HookEvent::Push(vec!["master".to_string()])
+        );
+        assert_eq!(
+            parse_event(
+                &headers(&[("x-event-key", "repo:refs_changed")]),
+                br#"{"changes": [{"refId": "refs/heads/master"}]}"#
+            ),
+            HookEvent::Push(vec!["refs/heads/master".to_string()])
+        );
+        assert_eq!(
+            parse_event(&HeaderMap::new(), b"not json"),
+            HookEvent::Push(vec![])
+        );
+    }
+}
@@ -1,17 +1,23 @@
+use crate::auth::{redact, without_password, GitAuth};
 use crate::error::HoganError;
-use crate::find_file_paths;
-use crate::git;
+use crate::git::{self, CloneOptions, NativeGit, TreeFiles};
+use crate::signature::AllowedSigners;
+use crate::{find_file_paths, walk_files, IGNORE_FILE};
 use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use json_patch::merge;
 use regex::Regex;
 use regex::RegexBuilder;
 use serde_json::{self, Value};
-use std::fs::File;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::str::{self, FromStr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tempfile::{self, TempDir};
 use url::{ParseError, Url};
-use walkdir::WalkDir;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ConfigUrl {
@@ -20,6 +26,7 @@ pub enum ConfigUrl {
     },
     Git {
         url: Url,
+        /// A branch, tag or fully qualified ref to check out, given as the url fragment
         branch: Option<String>,
         internal_path: PathBuf,
     },
@@ -32,6 +39,9 @@ impl FromStr for ConfigUrl {
         match Url::parse(s) {
             Ok(url) => {
                 if url.scheme() == "file" {
+                    if let Some(git_url) = local_git_url(s.trim_start_matches("file://")) {
+                        return Ok(git_url);
+                    }
                     Ok(ConfigUrl::File {
                         path: PathBuf::from(s.trim_start_matches("file://")),
                     })
@@ -85,7 +95,9 @@ impl FromStr for ConfigUrl {
                     }
                 }
             }
-            Err(ParseError::RelativeUrlWithoutBase) => if s.contains(".git") {
+            Err(ParseError::RelativeUrlWithoutBase) => if let Some(git_url) = local_git_url(s) {
+                return Ok(git_url);
+            } else if s.contains(".git") {
                 format!("ssh://{}", str::replace(s, ":", "/"))
             } else {
                 format!("file://{}", s)
@@ -96,30 +108,127 @@ impl FromStr for ConfigUrl {
     }
 }
 
+/// Treats a local path as a git repository when one of its directories is named `*.git` and is a
+/// repository, such as a bare repository or the `.git` directory of a working copy. A path with a
+/// `#fragment` is also read from git when it is within a working copy. As with remote urls, the
+/// rest of the path is the path within the repository and the fragment is the branch
+fn local_git_url(s: &str) -> Option<ConfigUrl> {
+    let (path, branch) = match s.split_once('#') {
+        Some((path, branch)) => (Path::new(path), Some(branch.to_owned())),
+        None => (Path::new(s), None),
+    };
+    let is_repo = |dir: &Path| git2::Repository::open(dir).is_ok();
+    let repo_path = path
+        .ancestors()
+        .find(|dir| {
+            dir.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.ends_with(".git"))
+                .unwrap_or(false)
+                && is_repo(dir)
+        })
+        .or_else(|| {
+            // Without a branch, a directory in a working copy is read as it is, uncommitted
+            // changes and all
+            branch.as_ref()?;
+            path.ancestors()
+                .find(|dir| dir.join(".git").exists() && is_repo(dir))
+        })?;
+    let internal_path = path.strip_prefix(repo_path).ok()?.to_path_buf();
+
+    Some(ConfigUrl::Git {
+        url: Url::from_file_path(repo_path.canonicalize().ok()?).ok()?,
+        branch,
+        internal_path,
+    })
+}
+
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum ConfigDir {
     File {
         directory: PathBuf,
+        /// Also skip the config files ignored by .gitignore files
+        gitignore: bool,
     },
     Git {
         url: Url,
         head_sha: String,
-        ssh_key_path: PathBuf,
+        auth: GitAuth,
         temp_dir: TempDir,
         directory: PathBuf,
         native_git: bool,
         native_fetch: bool,
         native_clone: bool,
+        native: NativeGit,
+        clone_options: CloneOptions,
+        sparse_path: Option<PathBuf>,
+        /// Remotes fetched from in turn when origin can not be, with their urls
+        fallback_remotes: Vec<(String, Url)>,
+        fetch_hooks: FetchHooks,
+        /// Also skip the config files ignored by .gitignore files of the checkout
+        gitignore: bool,
     },
 }
 
+/// Asked before each fetch with the remote to fetch from. An error stops the fetch
+pub type FetchGate = Arc<dyn Fn(&str) -> Result<()> + Send + Sync>;
+
+/// Told the config directory, remote, duration and success of each fetch
+pub type FetchObserver = Arc<dyn Fn(&ConfigDir, &str, Duration, bool) + Send + Sync>;
+
+/// Run around every fetch of a git config repository, whichever lookup it was made for
+#[derive(Clone, Default)]
+pub struct FetchHooks {
+    pub gate: Option<FetchGate>,
+    pub observer: Option<FetchObserver>,
+}
+
+impl fmt::Debug for FetchHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FetchHooks")
+            .field("gate", &self.gate.is_some())
+            .field("observer", &self.observer.is_some())
+            .finish()
+    }
+}
+
+/// A persistent bare mirror of git configs, kept between runs so clones only fetch what changed
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    pub cache_dir: PathBuf,
+    /// Use the mirror as is, without contacting the remote
+    pub offline: bool,
+}
+
+impl Mirror {
+    /// Each repository is mirrored in its own directory, named after its host and path
+    pub fn path(&self, url: &Url) -> PathBuf {
+        let name = format!("{}{}", url.host_str().unwrap_or_default(), url.path())
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        self.cache_dir.join(name)
+    }
+}
+
 impl ConfigDir {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         url: ConfigUrl,
-        ssh_key_path: &Path,
+        auth: &GitAuth,
         native_git: bool,
         native_fetch: bool,
         native_clone: bool,
+        native: NativeGit,
+        clone_options: CloneOptions,
+        mirror: Option<&Mirror>,
     ) -> Result<ConfigDir> {
         let config_dir = match url {
             ConfigUrl::Git {
@@ -131,11 +240,85 @@ impl ConfigDir {
                     msg: format!("Unable to create temp directory {:?}", e),
                 })?;
 
-                let git_repo = if native_git && native_clone {
-                    git::ext_clone(&url, temp_dir.path())?;
-                    git::build_repo(temp_dir.path().to_str().unwrap())?
+                // Native git authenticates with the same SSH key as libgit2
+                let native = NativeGit {
+                    ssh_key: native
+                        .ssh_key
+                        .or_else(|| Some(auth.ssh.key_path()).filter(|key| key.is_file())),
+                    known_hosts: native.known_hosts.or_else(|| auth.ssh.known_hosts.clone()),
+                    host_key_checking: auth.ssh.host_key_checking,
+                    ..native
+                };
+                let sparse_path = if clone_options.sparse && internal_path.components().count() > 0
+                {
+                    Some(internal_path.clone())
                 } else {
-                    git::clone(&url, branch.as_deref(), temp_dir.path(), Some(ssh_key_path))?
+                    None
+                };
+                if clone_options.is_partial() && !(native_git && native_clone) {
+                    return Err(HoganError::InvalidConfiguration {
+                        param: "depth".to_string(),
+                        msg:
+                            "Shallow and blobless clones need native git clones (--git --git-clone)"
+                                .to_string(),
+                    }
+                    .into());
+                }
+
+                // Native clones are taken straight from the remote, so that git itself resolves
+                // relative submodule urls against it rather than the mirror
+                let git_repo = match mirror {
+                    Some(mirror) if native_git && native_clone && mirror.offline => {
+                        return Err(HoganError::InvalidConfiguration {
+                            param: "offline".to_string(),
+                            msg: "Native git clones (--git --git-clone) do not use the cache-dir \
+                                  mirror, so can not be made offline"
+                                .to_string(),
+                        }
+                        .into())
+                    }
+                    Some(mirror) if !(native_git && native_clone) => {
+                        let mirror_path = mirror.path(&url);
+                        fs::create_dir_all(&mirror.cache_dir).with_context(|| {
+                            format!("Unable to create cache directory {:?}", mirror.cache_dir)
+                        })?;
+                        git::mirror(&url, &mirror_path, Some(auth), mirror.offline)?;
+                        let mirror_url = fs::canonicalize(&mirror_path)
+                            .ok()
+                            .and_then(|path| Url::from_file_path(path).ok())
+                            .ok_or_else(|| HoganError::GitError {
+                                msg: format!("Invalid mirror path {:?}", mirror_path),
+                            })?;
+                        git::clone(
+                            &mirror_url,
+                            branch.as_deref(),
+                            temp_dir.path(),
+                            None,
+                            sparse_path.as_deref(),
+                        )?
+                    }
+                    _ if native_git && native_clone => {
+                        git::ext_clone(
+                            &url,
+                            temp_dir.path(),
+                            &clone_options,
+                            sparse_path.as_deref(),
+                            &native,
+                        )?;
+                        let git_repo = git::build_repo(temp_dir.path().to_str().unwrap())?;
+                        if let Some(branch) = &branch {
+                            let sha = git::find_branch_head(&git_repo, "origin", branch)?;
+                            git::ext_reset(temp_dir.path(), "origin", &sha, false, &native)?;
+                        }
+                        git_repo
+                    }
+                    _ => git::clone(
+                        &url,
+                        branch.as_deref(),
+                        temp_dir.path(),
+                        Some(auth),
+                        sparse_path.as_deref(),
+                    )?,
                 };
 
                 let head_sha = git::get_head_sha(&git_repo)?;
@@ -149,20 +332,31 @@ impl ConfigDir {
                         .into())
                     }
                 };
-                let ssh_key_path = ssh_key_path.to_owned();
+                let auth = auth.clone();
 
-                Ok(ConfigDir::Git {
+                let config_dir = ConfigDir::Git {
                     url,
                     head_sha,
-                    ssh_key_path,
+                    auth,
                     temp_dir,
                     directory,
                     native_git,
                     native_fetch,
                     native_clone,
-                })
+                    native,
+                    clone_options,
+                    sparse_path,
+                    fallback_remotes: Vec::new(),
+                    fetch_hooks: FetchHooks::default(),
+                    gitignore: false,
+                };
+                config_dir.update_submodules()?;
+                Ok(config_dir)
             }
-            ConfigUrl::File { path } => Ok(ConfigDir::File { directory: path }),
+            ConfigUrl::File { path } => Ok(ConfigDir::File {
+                directory: path,
+                gitignore: false,
+            }),
         };
 
         if let Ok(ref config_dir) = config_dir {
@@ -184,22 +378,38 @@ impl ConfigDir {
         match self {
             ConfigDir::Git {
                 url,
-                ssh_key_path,
+                auth,
                 native_git,
                 native_clone,
                 native_fetch,
+                native,
+                clone_options,
+                fallback_remotes,
+                fetch_hooks,
+                gitignore,
                 ..
-            } => ConfigDir::new(
-                ConfigUrl::Git {
-                    url: url.clone(),
-                    branch: Some(branch.to_owned()),
-                    internal_path: PathBuf::new(),
-                },
-                ssh_key_path,
-                *native_git,
-                *native_fetch,
-                *native_clone,
-            ),
+            } => {
+                let mut config_dir = ConfigDir::new(
+                    ConfigUrl::Git {
+                        url: url.clone(),
+                        branch: Some(branch.to_owned()),
+                        internal_path: PathBuf::new(),
+                    },
+                    auth,
+                    *native_git,
+                    *native_fetch,
+                    *native_clone,
+                    native.clone(),
+                    clone_options.clone(),
+                    None,
+                )?;
+                for (_, url) in fallback_remotes {
+                    config_dir.add_fallback_remote(url)?;
+                }
+                config_dir.set_fetch_hooks(fetch_hooks.clone());
+                config_dir.set_gitignore(*gitignore);
+                Ok(config_dir)
+            }
             ConfigDir::File { .. } => Err(HoganError::GitError {
                 msg: "Can not extend file config".to_string(),
             }
@@ -214,11 +424,59 @@ impl ConfigDir {
         }
     }
 
+    pub fn head_sha(&self) -> Option<&str> {
+        match *self {
+            ConfigDir::File { .. } => None,
+            ConfigDir::Git { ref head_sha, .. } => Some(head_sha),
+        }
+    }
+
+    /// Checks out a SHA of a git config repository, fetching it if it is not found
+    pub fn checkout(&mut self, sha: &str) -> Result<()> {
+        let checked_out = self.refresh(None, Some(sha), true)?;
+        if let ConfigDir::Git {
+            ref mut head_sha, ..
+        } = *self
+        {
+            *head_sha = checked_out;
+        }
+        Ok(())
+    }
+
+    /// Checks out a SHA, or finds the head when there is no target. Missing SHAs are fetched from
+    /// the remote when `allow_fetch` is set. Without a remote, origin is fetched from, then each
+    /// fallback remote when that fails
     pub fn refresh(
         &self,
         remote: Option<&str>,
         target: Option<&str>,
         allow_fetch: bool,
+    ) -> Result<String> {
+        let remotes = match remote {
+            Some(remote) => vec![remote.to_owned()],
+            None => self.remotes(),
+        };
+        let mut result = Err(HoganError::GitError {
+            msg: "No remotes to refresh from".to_string(),
+        }
+        .into());
+        for remote in remotes {
+            result = self.refresh_from(&remote, target, allow_fetch);
+            match &result {
+                // The remote was fetched from, it just doesn't have the SHA
+                Err(e) if matches!(e.downcast_ref(), Some(HoganError::UnknownSHA { .. })) => break,
+                Err(e) => warn!("Unable to refresh from {}: {:?}", remote, e),
+                Ok(_) => break,
+            }
+        }
+        result
+    }
+
+    fn refresh_from(
+        &self,
+        remote: &str,
+        target: Option<&str>,
+        allow_fetch: bool,
     ) -> Result<String> {
         match self {
             ConfigDir::File { .. } => Err(HoganError::GitError {
@@ -227,63 +485,138 @@ impl ConfigDir {
             .into()),
             ConfigDir::Git {
                 directory,
-                url,
-                ssh_key_path,
+                auth,
+                clone_options,
+                sparse_path,
+                native,
                 ..
             } => {
-                let git_repo = git::build_repo(directory.to_str().unwrap())
-                    .with_context(|| "Attempting to refresh git repo -- Building Repo")?;
+                let reset = |fetch_sha: bool| {
+                    if let (true, Some(target)) = (clone_options.is_partial(), target) {
+                        git::ext_reset(directory, remote, target, fetch_sha, native)
+                    } else {
+                        let git_repo = git::build_repo(directory.to_str().unwrap())
+                            .with_context(|| "Attempting to refresh git repo -- Building Repo")?;
+
+                        git::reset(
+                            &git_repo,
+                            remote,
+                            Some(auth),
+                            self.remote_url(remote),
+                            target,
+                            false,
+                            false,
+                            sparse_path.as_deref(),
+                        )
+                    }
+                    .with_context(|| format!("Error refreshing to {:?}", target))
+                };
+
+                let sha = match reset(false) {
+                    Err(e)
+                        if allow_fetch
+                            && matches!(e.downcast_ref(), Some(HoganError::UnknownSHA { .. })) =>
+                    {
+                        info!("Couldn't find {:?}. Trying to refreshing repo", target);
+                        self.fetch_only(remote)?;
+                        reset(true)?
+                    }
+                    result => result?,
+                };
 
-                git::reset(
-                    &git_repo,
-                    remote.unwrap_or("origin"),
-                    Some(ssh_key_path),
-                    Some(url),
-                    target,
-                    false,
-                    allow_fetch,
-                )
-                .with_context(|| format!("Error refreshing to {:?}", target))
+                if target.is_some() {
+                    self.update_submodules()?;
+                }
+                Ok(sha)
             }
         }
     }
 
-    pub fn find(&self, filter: Regex) -> Vec<Environment> {
-        fn find_env_type_data<'a>(types: &'a [EnvironmentType], name: &str) -> &'a Value {
-            types
-                .iter()
-                .find(|e| e.environment_type == name)
-                .map(|env| &env.config_data)
-                .unwrap_or(&Value::Null)
+    // Checks out the submodules of the current commit, when they are wanted
+    fn update_submodules(&self) -> Result<()> {
+        match self {
+            ConfigDir::Git {
+                url,
+                auth,
+                temp_dir,
+                native_git,
+                native_clone,
+                native,
+                clone_options,
+                sparse_path,
+                ..
+            } if clone_options.submodules => {
+                if clone_options.is_partial() || (*native_git && *native_clone) {
+                    git::ext_update_submodules(temp_dir.path(), sparse_path.as_deref(), native)
+                } else {
+                    let git_repo = git::build_repo(temp_dir.path().to_str().unwrap())
+                        .with_context(|| "Updating submodules -- Building Repo")?;
+                    git::update_submodules(&git_repo, Some(url), Some(auth), sparse_path.as_deref())
+                }
+            }
+            _ => Ok(()),
         }
+    }
 
+    pub fn find(&self, filter: Regex) -> Vec<Environment> {
         let environment_types =
             ConfigDir::find_environment_types(self).collect::<Vec<EnvironmentType>>();
-        let global = find_env_type_data(&environment_types, "global");
+        merge_environment_types(
+            ConfigDir::find_environments(self, filter),
+            &environment_types,
+        )
+    }
 
-        ConfigDir::find_environments(self, filter)
-            .map(|mut environment| {
-                let parent = if let Some(ref env_type_name) = environment.environment_type {
-                    find_env_type_data(&environment_types, env_type_name)
-                } else {
-                    &Value::Null
+    /// Finds environments in a commit of a git config repository by reading its tree rather than
+    /// checking it out, so finds for different SHAs can run concurrently. The repository is only
+    /// fetched when the SHA is not found and `allow_fetch` is set. Returns the full SHA
+    pub fn find_at(
+        &self,
+        remote: Option<&str>,
+        sha: &str,
+        filter: Regex,
+        allow_fetch: bool,
+    ) -> Result<(String, Vec<Environment>)> {
+        match self {
+            ConfigDir::File { .. } => Err(HoganError::GitError {
+                msg: "Cannot read a commit of a file config".to_string(),
+            }
+            .into()),
+            ConfigDir::Git {
+                clone_options: CloneOptions { blobless: true, .. },
+                ..
+            } => Err(HoganError::GitError {
+                msg: "Cannot read the trees of a blobless clone".to_string(),
+            }
+            .into()),
+            ConfigDir::Git { directory, .. } => {
+                let git_repo = git::build_repo(directory.to_str().unwrap())
+                    .with_context(|| "Attempting to read git tree -- Building Repo")?;
+                let internal_path = git_repo
+                    .workdir()
+                    .and_then(|workdir| directory.strip_prefix(workdir).ok())
+                    .unwrap_or_else(|| Path::new(""));
+
+                let keep = |path: &Path| is_config_file(path, &filter);
+                let (sha, files) = match git::read_tree(&git_repo, sha, internal_path, keep) {
+                    Err(e)
+                        if allow_fetch
+                            && matches!(e.downcast_ref(), Some(HoganError::UnknownSHA { .. })) =>
+                    {
+                        info!("Couldn't find {}. Trying to refreshing repo", sha);
+                        self.fetch_with_fallback(remote.unwrap_or("origin"))?;
+                        git::read_tree(&git_repo, sha, internal_path, keep)?
+                    }
+                    result => result?,
                 };
-
-                let mut config_data = Value::Null; // Start with Null to remove Null values from contexts
-
-                merge(&mut config_data, global); // Merge in global config
-                merge(&mut config_data, parent); // Merge in an env type
-                merge(&mut config_data, &environment.config_data); // Merge with the actual config
-
-                environment.config_data = config_data;
-                environment
-            })
-            .collect()
+                Ok((sha, find_in_files(without_ignored(files), &filter)))
+            }
+        }
     }
 
     fn find_environments(&self, filter: Regex) -> Box<dyn Iterator<Item = Environment>> {
         Box::new(
-            find_file_paths(self.directory(), filter)
+            find_file_paths(self.directory(), filter, self.gitignore())
                 .filter_map(|p| File::open(p).ok())
                 .filter_map(|f| serde_json::from_reader(f).ok())
                 .filter_map(|c: Config| c.into_environment()),
@@ -292,25 +625,63 @@ impl ConfigDir {
 
     fn find_environment_types(&self) -> Box<dyn Iterator<Item = EnvironmentType>> {
         Box::new(
-            WalkDir::new(self.directory())
-                .into_iter()
-                .filter_map(|e| e.ok())
-                .filter(|e| e.file_type().is_file())
-                .filter_map(|e| {
-                    let path = e.path();
-                    let env_type = path.file_stem().unwrap().to_string_lossy().into_owned();
-                    File::open(path)
-                        .ok()
-                        .and_then(|f| serde_json::from_reader(f).ok())
-                        .and_then(|c: Config| c.into_environment_type())
-                        .map(|mut e| {
-                            e.environment_type = env_type;
-                            e
-                        })
-                }),
+            walk_files(self.directory(), self.gitignore()).filter_map(|path| {
+                let env_type = path.file_stem().unwrap().to_string_lossy().into_owned();
+                File::open(&path)
+                    .ok()
+                    .and_then(|f| serde_json::from_reader(f).ok())
+                    .and_then(|c: Config| c.into_environment_type())
+                    .map(|mut e| {
+                        e.environment_type = env_type;
+                        e
+                    })
+            }),
         )
     }
 
+    /// Resolves a SHA, which may be abbreviated, to the full id of a commit. The commit is not
+    /// fetched, so an unknown SHA is an error
+    pub fn commit_id(&self, sha: &str) -> Result<String> {
+        match self {
+            ConfigDir::File { .. } => Err(HoganError::GitError {
+                msg: "File configs have no commits".to_string(),
+            }
+            .into()),
+            ConfigDir::Git { directory, .. } => {
+                let git_repo = git::build_repo(directory.to_str().unwrap())
+                    .with_context(|| "Resolving commit")?;
+                git_repo
+                    .revparse_single(sha)
+                    .and_then(|object| object.peel_to_commit())
+                    .map(|commit| commit.id().to_string())
+                    .map_err(|_| {
+                        HoganError::UnknownSHA {
+                            sha: sha.to_string(),
+                        }
+                        .into()
+                    })
+            }
+        }
+    }
+
+    /// Rejects a commit which is not signed by one of the allowed signers. The commit is not
+    /// fetched, so an unknown SHA is an error
+    pub fn verify_commit(&self, sha: &str, signers: &AllowedSigners) -> Result<()> {
+        match self {
+            ConfigDir::File { .. } => Err(HoganError::UntrustedCommit {
+                sha: sha.to_string(),
+                msg: "File configs have no commits to verify".to_string(),
+            }
+            .into()),
+            ConfigDir::Git {
+                directory, native, ..
+            } => {
+                let commit_id = self.commit_id(sha)?;
+                signers.verify_commit(native, directory, &commit_id)
+            }
+        }
+    }
+
     pub fn find_branch_head(
         &self,
         remote_name: &str,
@@ -322,21 +693,17 @@ impl ConfigDir {
                 msg: "Unable to perform git actions on a file".to_string(),
             })
             .context("Finding branch head"),
-            ConfigDir::Git {
-                directory,
-                ssh_key_path,
-                url,
-                ..
-            } => {
+            ConfigDir::Git { directory, .. } => {
                 let git_repo = git::build_repo(directory.to_str().unwrap())
                     .with_context(|| "Finding branch head")?;
 
-                if refresh {
-                    git::fetch(&git_repo, remote_name, Some(ssh_key_path), Some(url))
+                // While fetches are gated the head is found from the refs already fetched
+                if refresh && self.fetch_allowed(remote_name) {
+                    self.fetch_with_fallback(remote_name)
                         .with_context(|| "Finding branch head, updating repo")?;
                 }
 
-                git::find_branch_head(&git_repo, &format!("{}/{}", remote_name, branch_name))
+                git::find_branch_head(&git_repo, remote_name, branch_name)
                     .with_context(|| "Finding branch head, querying for head")
             }
         }
@@ -351,10 +718,11 @@ impl ConfigDir {
             ConfigDir::Git {
                 directory,
                 native_git,
+                native,
                 ..
             } => {
                 if *native_git {
-                    git::ext_maintenance(directory.as_path())
+                    git::ext_maintenance(directory.as_path(), native)
                         .with_context(|| "Performing Maintenance")?;
                 }
                 Ok(())
@@ -362,6 +730,48 @@ impl ConfigDir {
         }
     }
 
+    /// Size in bytes of the object database of the repository, loose objects and packs alike
+    pub fn repository_size(&self) -> Result<u64> {
+        match self {
+            ConfigDir::File { .. } => Err(HoganError::GitError {
+                msg: "Unable to perform git actions on a file".to_string(),
+            })
+            .context("Measuring repo size"),
+            ConfigDir::Git { directory, .. } => {
+                let git_repo = git::build_repo(directory.to_str().unwrap())
+                    .with_context(|| "Measuring repo size")?;
+                directory_size(&git_repo.path().join("objects"))
+                    .with_context(|| "Measuring repo size")
+            }
+        }
+    }
+
+    /// The commit each reference of the repository points to, so callers can tell when a fetch has
+    /// moved any branch or tag
+    pub fn ref_targets(&self) -> Result<HashMap<String, String>> {
+        match self {
+            ConfigDir::File { .. } => Err(HoganError::GitError {
+                msg: "Unable to perform git actions on a file".to_string(),
+            })
+            .context("Listing refs"),
+            ConfigDir::Git { directory, .. } => {
+                let git_repo =
+                    git::build_repo(directory.to_str().unwrap()).with_context(|| "Listing refs")?;
+                let references = git_repo.references().with_context(|| "Listing refs")?;
+                Ok(references
+                    .flatten()
+                    .filter_map(|reference| {
+                        Some((
+                            reference.name()?.to_owned(),
+                            reference.target()?.to_string(),
+                        ))
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Fetches from a remote, once the fetch gate allows it
     pub fn fetch_only(&self, remote_name: &str) -> Result<()> {
         match self {
             ConfigDir::File { .. } => Err(HoganError::GitError {
@@ -370,26 +780,290 @@ impl ConfigDir {
             .context("Fetching git repo"),
             ConfigDir::Git {
                 directory,
-                ssh_key_path,
-                url,
+                auth,
                 native_git,
                 native_fetch,
+                clone_options,
+                native,
+                fetch_hooks,
                 ..
             } => {
-                if *native_git && *native_fetch {
-                    git::ext_fetch(directory.as_path(), remote_name)
-                        .with_context(|| "Fetching git repo")?;
-                    Ok(())
+                if let Some(gate) = &fetch_hooks.gate {
+                    gate(remote_name).with_context(|| format!("Not fetching {}", remote_name))?;
+                }
+
+                let start = Instant::now();
+                let url = self.remote_url(remote_name);
+                let result = if (*native_git && *native_fetch) || clone_options.is_partial() {
+                    git::ext_fetch(directory.as_path(), remote_name, native)
+                        .with_context(|| "Fetching git repo")
                 } else {
-                    let git_repo = git::build_repo(directory.to_str().unwrap())
-                        .with_context(|| "Fetching git repo. Building repo")?;
-                    git::fetch(&git_repo, remote_name, Some(ssh_key_path), Some(url))
-                        .with_context(|| "Fetching Repo")?;
-                    Ok(())
+                    git::build_repo(directory.to_str().unwrap())
+                        .with_context(|| "Fetching git repo. Building repo")
+                        .and_then(|git_repo| {
+                            git::fetch(&git_repo, remote_name, Some(auth), url)
+                                .with_context(|| "Fetching Repo")
+                        })
+                };
+
+                let result = result.and_then(|_| self.fast_forward_origin(remote_name));
+                if let Some(observer) = &fetch_hooks.observer {
+                    observer(self, remote_name, start.elapsed(), result.is_ok());
                 }
+                result
             }
         }
     }
+
+    /// Whether the fetch gate allows fetching from a remote now
+    pub fn fetch_allowed(&self, remote_name: &str) -> bool {
+        match self {
+            ConfigDir::Git {
+                fetch_hooks: FetchHooks {
+                    gate: Some(gate), ..
+                },
+                ..
+            } => match gate(remote_name) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("Not fetching {}: {:#}", remote_name, e);
+                    false
+                }
+            },
+            _ => true,
+        }
+    }
+
+    /// Fetches from a remote. When origin can not be fetched from, each fallback remote is tried
+    /// in turn. Returns the remote which was fetched from
+    pub fn fetch_with_fallback(&self, remote_name: &str) -> Result<String> {
+        let remotes = if remote_name == "origin" {
+            self.remotes()
+        } else {
+            vec![remote_name.to_owned()]
+        };
+        let mut result = Err(HoganError::GitError {
+            msg: "No remotes to fetch from".to_string(),
+        }
+        .into());
+        for remote in remotes {
+            result = self.fetch_only(&remote);
+            match &result {
+                Ok(_) => return Ok(remote),
+                Err(e) => warn!("Unable to fetch from {}: {:?}", remote, e),
+            }
+        }
+        result.map(|_| remote_name.to_owned())
+    }
+
+    /// Adds a read-only mirror of origin, fetched from when origin can not be. Its branches are
+    /// fetched under its own name, and move the branches of origin forward when they are ahead of
+    /// them, so branch heads and SHAs are found as usual while origin is unavailable but a mirror
+    /// which lags behind never rewinds them. Returns the name of the remote
+    pub fn add_fallback_remote(&mut self, fallback_url: &Url) -> Result<String> {
+        match self {
+            ConfigDir::File { .. } => Err(HoganError::GitError {
+                msg: "Unable to add remotes to a file config".to_string(),
+            }
+            .into()),
+            ConfigDir::Git {
+                temp_dir,
+                fallback_remotes,
+                ..
+            } => {
+                let name = format!("fallback-{}", fallback_remotes.len() + 1);
+                let git_repo = git::build_repo(temp_dir.path().to_str().unwrap())
+                    .with_context(|| "Adding fallback remote")?;
+                git_repo
+                    .remote_with_fetch(
+                        &name,
+                        without_password(fallback_url).as_str(),
+                        &format!("refs/heads/*:refs/remotes/{}/*", name),
+                    )
+                    .map_err::<HoganError, _>(|e| e.into())
+                    .with_context(|| format!("Error adding remote {}", redact(fallback_url)))?;
+                info!("Falling back to {} as {}", redact(fallback_url), name);
+                fallback_remotes.push((name.clone(), fallback_url.clone()));
+                Ok(name)
+            }
+        }
+    }
+
+    // Moves the branches of origin forward to those just fetched from a fallback remote
+    fn fast_forward_origin(&self, remote_name: &str) -> Result<()> {
+        match self {
+            ConfigDir::Git {
+                directory,
+                fallback_remotes,
+                ..
+            } if fallback_remotes.iter().any(|(name, _)| name == remote_name) => {
+                let git_repo = git::build_repo(directory.to_str().unwrap())
+                    .with_context(|| "Updating origin from fallback remote")?;
+                git::fast_forward_remote(&git_repo, remote_name, "origin")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets the hooks run around every fetch, such as those made to find an unknown SHA
+    /// Also skips the config files ignored by .gitignore files when finding environments in the
+    /// directory. Reads of git trees only skip the files ignored by .hoganignore files
+    pub fn set_gitignore(&mut self, value: bool) {
+        match self {
+            ConfigDir::File { gitignore, .. } | ConfigDir::Git { gitignore, .. } => {
+                *gitignore = value
+            }
+        }
+    }
+
+    fn gitignore(&self) -> bool {
+        match *self {
+            ConfigDir::File { gitignore, .. } | ConfigDir::Git { gitignore, .. } => gitignore,
+        }
+    }
+
+    pub fn set_fetch_hooks(&mut self, hooks: FetchHooks) {
+        if let ConfigDir::Git { fetch_hooks, .. } = self {
+            *fetch_hooks = hooks;
+        }
+    }
+
+    /// The remotes to fetch from, origin first and then the fallbacks in the order they were added
+    pub fn remotes(&self) -> Vec<String> {
+        let mut remotes = vec!["origin".to_owned()];
+        if let ConfigDir::Git {
+            fallback_remotes, ..
+        } = self
+        {
+            remotes.extend(fallback_remotes.iter().map(|(name, _)| name.clone()));
+        }
+        remotes
+    }
+
+    // The url of a remote, for credentials
+    fn remote_url(&self, remote_name: &str) -> Option<&Url> {
+        match self {
+            ConfigDir::Git {
+                url,
+                fallback_remotes,
+                ..
+            } => Some(
+                fallback_remotes
+                    .iter()
+                    .find(|(name, _)| name == remote_name)
+                    .map_or(url, |(_, url)| url),
+            ),
+            ConfigDir::File { .. } => None,
+        }
+    }
+}
+
+fn merge_environment_types(
+    environments: impl Iterator<Item = Environment>,
+    environment_types: &[EnvironmentType],
+) -> Vec<Environment> {
+    fn find_env_type_data<'a>(types: &'a [EnvironmentType], name: &str) -> &'a Value {
+        types
+            .iter()
+            .find(|e| e.environment_type == name)
+            .map(|env| &env.config_data)
+            .unwrap_or(&Value::Null)
+    }
+
+    let global = find_env_type_data(environment_types, "global");
+
+    environments
+        .map(|mut environment| {
+            let parent = if let Some(ref env_type_name) = environment.environment_type {
+                find_env_type_data(environment_types, env_type_name)
+            } else {
+                &Value::Null
+            };
+
+            let mut config_data = Value::Null; // Start with Null to remove Null values from contexts
+
+            merge(&mut config_data, global); // Merge in global config
+            merge(&mut config_data, parent); // Merge in an env type
+            merge(&mut config_data, &environment.config_data); // Merge with the actual config
+
+            environment.config_data = config_data;
+            environment
+        })
+        .collect()
+}
+
+// The equivalent of find for files read from a git tree
+fn find_in_files(files: TreeFiles, filter: &Regex) -> Vec<Environment> {
+    let mut environments = Vec::new();
+    let mut environment_types = Vec::new();
+    for (path, contents) in files {
+        match serde_json::from_slice(&contents) {
+            Ok(Config::Environment(environment))
+                if path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map(|name| filter.is_match(name))
+                    .unwrap_or(false) =>
+            {
+                environments.push(environment)
+            }
+            Ok(Config::EnvironmentType(mut environment_type)) => {
+                environment_type.environment_type =
+                    path.file_stem().unwrap().to_string_lossy().into_owned();
+                environment_types.push(environment_type)
+            }
+            _ => {}
+        }
+    }
+    merge_environment_types(environments.into_iter(), &environment_types)
+}
+
+// Whether a file of a git tree may hold an environment the filter matches, an environment type or
+// ignore rules, so that no other files are read
+fn is_config_file(path: &Path, filter: &Regex) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name == IGNORE_FILE || name.ends_with(".json") || filter.is_match(name),
+        None => false,
+    }
+}
+
+// Drops the files matched by a .hoganignore in their directory or a parent, as walk_files does
+fn without_ignored(files: TreeFiles) -> TreeFiles {
+    let ignores = files
+        .iter()
+        .filter(|(path, _)| path.file_name() == Some(IGNORE_FILE.as_ref()))
+        .filter_map(|(path, contents)| {
+            let mut builder = GitignoreBuilder::new(path.parent().unwrap_or_else(|| Path::new("")));
+            for line in String::from_utf8_lossy(contents).lines() {
+                builder.add_line(None, line).ok()?;
+            }
+            builder.build().ok()
+        })
+        .collect::<Vec<Gitignore>>();
+
+    files
+        .into_iter()
+        .filter(|(path, _)| {
+            !ignores.iter().any(|ignore| {
+                path.starts_with(ignore.path())
+                    && ignore.matched_path_or_any_parents(path, false).is_ignore()
+            })
+        })
+        .collect()
+}
+
+fn directory_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            directory_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
 }
 
 #[derive(Debug, Deserialize)]
@@ -467,6 +1141,48 @@ mod tests {
     use super::*;
     use std::path::Path;
 
+    #[test]
+    fn test_find_gitignore() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["TEST", "LOCAL"] {
+            fs::write(
+                dir.path().join(format!("config.{}.json", name)),
+                format!(r#"{{"Environment": "{}", "ConfigData": {{}}}}"#, name),
+            )
+            .unwrap();
+        }
+        fs::write(dir.path().join(".gitignore"), "config.LOCAL.json\n").unwrap();
+        // .gitignore files only apply inside a git repository
+        fs::create_dir(dir.path().join(".git")).unwrap();
+
+        let mut config_dir = ConfigDir::new(
+            ConfigUrl::File {
+                path: dir.path().to_path_buf(),
+            },
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
+        )
+        .unwrap();
+        let found = |config_dir: &ConfigDir| {
+            let mut names = config_dir
+                .find(Regex::new(r"config\..+\.json$").unwrap())
+                .into_iter()
+                .map(|e| e.environment)
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        assert_eq!(found(&config_dir), vec!["LOCAL", "TEST"]);
+        config_dir.set_gitignore(true);
+        assert_eq!(found(&config_dir), vec!["TEST"]);
+    }
+
     #[test]
     fn test_github_url() {
         assert_eq!(
@@ -661,10 +1377,13 @@ mod tests {
     fn test_find_all_configs() {
         let config_dir = ConfigDir::new(
             "file://./tests/fixtures/configs".parse().unwrap(),
-            Path::new(""),
+            &GitAuth::default(),
             true,
             true,
             true,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
         )
         .unwrap();
         let environments = config_dir.find(build_regex("config\\..+\\.json$").unwrap());
@@ -675,13 +1394,842 @@ mod tests {
     fn test_find_subset_configs() {
         let config_dir = ConfigDir::new(
             "file://./tests/fixtures/configs".parse().unwrap(),
-            Path::new(""),
+            &GitAuth::default(),
             true,
             true,
             true,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
         )
         .unwrap();
         let environments = config_dir.find(build_regex(r#"config\.test\d?\.json"#).unwrap());
         assert_eq!(environments.len(), 2)
     }
+
+    // Writes files to a repository and commits them on top of its head, returning the new SHA
+    fn commit_configs(repo: &git2::Repository, files: &[(&str, &str)]) -> String {
+        let workdir = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (path, contents) in files {
+            fs::create_dir_all(workdir.join(path).parent().unwrap()).unwrap();
+            fs::write(workdir.join(path), contents).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("hogan", "hogan@example.com").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Update configs",
+            &tree,
+            parent.iter().collect::<Vec<_>>().as_slice(),
+        )
+        .unwrap()
+        .to_string()
+    }
+
+    #[test]
+    fn test_mirror_path() {
+        let mirror = Mirror {
+            cache_dir: PathBuf::from("cache"),
+            offline: false,
+        };
+        assert_eq!(
+            mirror.path(&Url::parse("ssh://git@github.com/foo/bar.git").unwrap()),
+            PathBuf::from("cache/github.com_foo_bar.git")
+        );
+    }
+
+    #[test]
+    fn test_mirror_configs() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(source_dir.path()).unwrap();
+        commit_configs(
+            &repo,
+            &[(
+                "config.TEST.json",
+                r#"{"Environment": "TEST", "ConfigData": {}}"#,
+            )],
+        );
+
+        let url = ConfigUrl::Git {
+            url: Url::from_file_path(source_dir.path()).unwrap(),
+            branch: None,
+            internal_path: PathBuf::new(),
+        };
+        let cache_dir = tempfile::tempdir().unwrap();
+        let mut mirror = Mirror {
+            cache_dir: cache_dir.path().to_path_buf(),
+            offline: true,
+        };
+
+        // Nothing has been mirrored yet
+        assert!(ConfigDir::new(
+            url.clone(),
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            Some(&mirror)
+        )
+        .is_err());
+
+        for offline in [false, true] {
+            mirror.offline = offline;
+            let config_dir = ConfigDir::new(
+                url.clone(),
+                &GitAuth::default(),
+                false,
+                false,
+                false,
+                NativeGit::default(),
+                CloneOptions::default(),
+                Some(&mirror),
+            )
+            .unwrap();
+            assert_eq!(
+                config_dir
+                    .find(build_regex("config\\..+\\.json$").unwrap())
+                    .len(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn test_native_clone_skips_mirror() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(source_dir.path()).unwrap();
+        commit_configs(
+            &repo,
+            &[(
+                "config.TEST.json",
+                r#"{"Environment": "TEST", "ConfigData": {}}"#,
+            )],
+        );
+
+        let url = Url::from_file_path(source_dir.path()).unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let mut mirror = Mirror {
+            cache_dir: cache_dir.path().join("cache"),
+            offline: false,
+        };
+        let new_config_dir = |mirror: &Mirror| {
+            ConfigDir::new(
+                ConfigUrl::Git {
+                    url: url.clone(),
+                    branch: None,
+                    internal_path: PathBuf::new(),
+                },
+                &GitAuth::default(),
+                true,
+                false,
+                true,
+                NativeGit::default(),
+                CloneOptions::default(),
+                Some(mirror),
+            )
+        };
+
+        let config_dir = new_config_dir(&mirror).unwrap();
+        let clone = git2::Repository::open(config_dir.directory()).unwrap();
+        assert_eq!(
+            clone.find_remote("origin").unwrap().url(),
+            Some(url.as_str())
+        );
+        assert!(!mirror.cache_dir.exists());
+
+        mirror.offline = true;
+        assert!(new_config_dir(&mirror).is_err());
+    }
+
+    #[test]
+    fn test_find_at() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(source_dir.path()).unwrap();
+        let first = commit_configs(
+            &repo,
+            &[
+                (
+                    "configs/config.TEST.json",
+                    r#"{"Environment": "TEST", "EnvironmentType": "test", "ConfigData": {"Version": 1}}"#,
+                ),
+                (
+                    "configs/test.json",
+                    r#"{"EnvironmentType": "test", "ConfigData": {"Type": "test"}}"#,
+                ),
+            ],
+        );
+        let second = commit_configs(
+            &repo,
+            &[
+                (
+                    "configs/config.TEST.json",
+                    r#"{"Environment": "TEST", "EnvironmentType": "test", "ConfigData": {"Version": 2}}"#,
+                ),
+                (
+                    "configs/old/config.OLD.json",
+                    r#"{"Environment": "OLD", "ConfigData": {}}"#,
+                ),
+                ("configs/.hoganignore", "old/"),
+                ("configs/README.md", "Not a config"),
+            ],
+        );
+
+        let config_dir = ConfigDir::new(
+            ConfigUrl::Git {
+                url: Url::from_file_path(source_dir.path()).unwrap(),
+                branch: None,
+                internal_path: PathBuf::from("configs"),
+            },
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
+        )
+        .unwrap();
+        let filter = build_regex("config\\..+\\.json$").unwrap();
+
+        let (sha, environments) = config_dir
+            .find_at(None, &first[..7], filter.clone(), false)
+            .unwrap();
+        assert_eq!(sha, first);
+        assert_eq!(environments.len(), 1);
+        assert_eq!(
+            environments[0].config_data,
+            serde_json::json!({"Version": 1, "Type": "test"})
+        );
+
+        let (sha, environments) = config_dir
+            .find_at(None, &second, filter.clone(), false)
+            .unwrap();
+        assert_eq!(sha, second);
+        assert_eq!(environments.len(), 1);
+        assert_eq!(environments[0].config_data["Version"], 2);
+
+        // The working directory is left at the head it was cloned at
+        assert_eq!(config_dir.head_sha(), Some(second.as_str()));
+
+        // Only files which may hold configs are read
+        let (_, files) = git::read_tree(&repo, &second, Path::new("configs"), |path| {
+            is_config_file(path, &filter)
+        })
+        .unwrap();
+        let mut paths = files.into_iter().map(|(path, _)| path).collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from(".hoganignore"),
+                PathBuf::from("config.TEST.json"),
+                PathBuf::from("old/config.OLD.json"),
+                PathBuf::from("test.json"),
+            ]
+        );
+
+        assert!(config_dir
+            .find_at(
+                None,
+                "0000000000000000000000000000000000000000",
+                filter,
+                false
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_local_git_repo() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(source_dir.path()).unwrap();
+        let first = commit_configs(
+            &repo,
+            &[(
+                "configs/config.TEST.json",
+                r#"{"Environment": "TEST", "ConfigData": {"Branch": "default"}}"#,
+            )],
+        );
+        let default_branch = repo.head().unwrap().shorthand().unwrap().to_owned();
+        repo.branch(
+            "feature",
+            &repo
+                .find_commit(git2::Oid::from_str(&first).unwrap())
+                .unwrap(),
+            false,
+        )
+        .unwrap();
+        repo.set_head("refs/heads/feature").unwrap();
+        let feature = commit_configs(
+            &repo,
+            &[(
+                "configs/config.TEST.json",
+                r#"{"Environment": "TEST", "ConfigData": {"Branch": "feature"}}"#,
+            )],
+        );
+
+        let git_dir = source_dir.path().join(".git");
+        let url = format!("file://{}/configs#feature", git_dir.display())
+            .parse::<ConfigUrl>()
+            .unwrap();
+        assert_eq!(
+            url,
+            ConfigUrl::Git {
+                url: Url::from_file_path(git_dir.canonicalize().unwrap()).unwrap(),
+                branch: Some("feature".to_string()),
+                internal_path: PathBuf::from("configs"),
+            }
+        );
+
+        // A directory of a working copy is read from git when a branch is given
+        let working_copy_url = ConfigUrl::Git {
+            url: Url::from_file_path(source_dir.path().canonicalize().unwrap()).unwrap(),
+            branch: Some("feature".to_string()),
+            internal_path: PathBuf::from("configs"),
+        };
+        assert_eq!(
+            format!("{}#feature", source_dir.path().join("configs").display())
+                .parse::<ConfigUrl>()
+                .unwrap(),
+            working_copy_url
+        );
+        assert_eq!(
+            format!(
+                "file://{}#feature",
+                source_dir.path().join("configs").display()
+            )
+            .parse::<ConfigUrl>()
+            .unwrap(),
+            working_copy_url
+        );
+
+        // Otherwise plain directories are still read as they are
+        assert!(matches!(
+            format!("file://{}", source_dir.path().join("configs").display())
+                .parse::<ConfigUrl>()
+                .unwrap(),
+            ConfigUrl::File { .. }
+        ));
+
+        let config_dir = ConfigDir::new(
+            url,
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(config_dir.head_sha(), Some(feature.as_str()));
+        let filter = build_regex("config\\..+\\.json$").unwrap();
+        assert_eq!(
+            config_dir.find(filter.clone())[0].config_data["Branch"],
+            "feature"
+        );
+
+        assert_eq!(
+            config_dir
+                .find_branch_head("origin", &default_branch, false)
+                .unwrap(),
+            first
+        );
+        assert_eq!(
+            config_dir.refresh(None, Some(&first), false).unwrap(),
+            first
+        );
+        assert_eq!(config_dir.find(filter)[0].config_data["Branch"], "default");
+    }
+
+    #[test]
+    fn test_submodules() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |env: &str, version| {
+            format!(
+                r#"{{"Environment": "{}", "ConfigData": {{"Version": {}}}}}"#,
+                env, version
+            )
+        };
+        let shared = git2::Repository::init(dir.path().join("shared")).unwrap();
+        commit_configs(&shared, &[("config.SHARED.json", &config("SHARED", 1))]);
+        let repo = git2::Repository::init(dir.path().join("configs")).unwrap();
+        commit_configs(&repo, &[("configs/config.TEST.json", &config("TEST", 1))]);
+
+        // Submodule urls relative to the repository are resolved against its url, not the mirror
+        let mut submodule = repo
+            .submodule("../shared", Path::new("configs/shared"), true)
+            .unwrap();
+        submodule.clone(None).unwrap();
+        submodule.add_finalize().unwrap();
+        let first = commit_configs(&repo, &[]);
+
+        let url = ConfigUrl::Git {
+            url: Url::from_file_path(repo.workdir().unwrap()).unwrap(),
+            branch: None,
+            internal_path: PathBuf::from("configs"),
+        };
+        let mirror = Mirror {
+            cache_dir: dir.path().join("cache"),
+            offline: false,
+        };
+        let filter = build_regex("config\\..+\\.json$").unwrap();
+        let new_config_dir = |submodules, mirror| {
+            ConfigDir::new(
+                url.clone(),
+                &GitAuth::default(),
+                false,
+                false,
+                false,
+                NativeGit::default(),
+                CloneOptions {
+                    submodules,
+                    ..Default::default()
+                },
+                mirror,
+            )
+            .unwrap()
+        };
+        let shared_version = |config_dir: &ConfigDir| {
+            config_dir
+                .find(filter.clone())
+                .into_iter()
+                .find(|environment| environment.environment == "SHARED")
+                .map(|environment| environment.config_data["Version"].clone())
+        };
+
+        assert_eq!(shared_version(&new_config_dir(false, None)), None);
+        assert_eq!(
+            shared_version(&new_config_dir(true, Some(&mirror))),
+            Some(1.into())
+        );
+
+        // Move the submodule to a new commit of the shared configs
+        let shared_second =
+            commit_configs(&shared, &[("config.SHARED.json", &config("SHARED", 2))]);
+        let submodule_repo = submodule.open().unwrap();
+        submodule_repo
+            .find_remote("origin")
+            .unwrap()
+            .fetch(&["+refs/heads/*:refs/remotes/origin/*"], None, None)
+            .unwrap();
+        submodule_repo
+            .set_head_detached(git2::Oid::from_str(&shared_second).unwrap())
+            .unwrap();
+        submodule_repo
+            .checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            .unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("configs/shared")).unwrap();
+        index.write().unwrap();
+        let second = commit_configs(&repo, &[]);
+
+        let config_dir = new_config_dir(true, None);
+        assert_eq!(shared_version(&config_dir), Some(2.into()));
+        assert_eq!(
+            config_dir.refresh(None, Some(&first), false).unwrap(),
+            first
+        );
+        assert_eq!(shared_version(&config_dir), Some(1.into()));
+        assert_eq!(
+            config_dir.refresh(None, Some(&second), false).unwrap(),
+            second
+        );
+        assert_eq!(shared_version(&config_dir), Some(2.into()));
+    }
+
+    #[test]
+    fn test_fallback_remotes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |version| {
+            format!(
+                r#"{{"Environment": "TEST", "ConfigData": {{"Version": {}}}}}"#,
+                version
+            )
+        };
+        let origin_path = dir.path().join("origin");
+        let origin = git2::Repository::init(&origin_path).unwrap();
+        commit_configs(&origin, &[("configs/config.TEST.json", &config(1))]);
+        let default_branch = origin.head().unwrap().shorthand().unwrap().to_owned();
+        let fallback_path = dir.path().join("fallback");
+        let fallback =
+            git2::Repository::clone(origin_path.to_str().unwrap(), &fallback_path).unwrap();
+
+        let mut config_dir = ConfigDir::new(
+            ConfigUrl::Git {
+                url: Url::from_file_path(&origin_path).unwrap(),
+                branch: None,
+                internal_path: PathBuf::from("configs"),
+            },
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            config_dir
+                .add_fallback_remote(&Url::from_file_path(&fallback_path).unwrap())
+                .unwrap(),
+            "fallback-1"
+        );
+        assert_eq!(config_dir.remotes(), vec!["origin", "fallback-1"]);
+        assert_eq!(config_dir.fetch_with_fallback("origin").unwrap(), "origin");
+
+        // The mirror keeps serving new commits while origin is unavailable
+        let second = commit_configs(&fallback, &[("configs/config.TEST.json", &config(2))]);
+        fs::rename(&origin_path, dir.path().join("moved")).unwrap();
+        assert!(config_dir.fetch_only("origin").is_err());
+        assert_eq!(
+            config_dir.refresh(None, Some(&second), true).unwrap(),
+            second
+        );
+
+        let third = commit_configs(&fallback, &[("configs/config.TEST.json", &config(3))]);
+        assert_eq!(
+            config_dir
+                .find_branch_head("origin", &default_branch, true)
+                .unwrap(),
+            third
+        );
+    }
+
+    #[test]
+    fn test_lagging_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |version| {
+            format!(
+                r#"{{"Environment": "TEST", "ConfigData": {{"Version": {}}}}}"#,
+                version
+            )
+        };
+        let origin_path = dir.path().join("origin");
+        let origin = git2::Repository::init(&origin_path).unwrap();
+        let first = commit_configs(&origin, &[("configs/config.TEST.json", &config(1))]);
+        let default_branch = origin.head().unwrap().shorthand().unwrap().to_owned();
+        let fallback_path = dir.path().join("fallback");
+        git2::Repository::clone(origin_path.to_str().unwrap(), &fallback_path).unwrap();
+        let second = commit_configs(&origin, &[("configs/config.TEST.json", &config(2))]);
+
+        let mut config_dir = ConfigDir::new(
+            ConfigUrl::Git {
+                url: Url::from_file_path(&origin_path).unwrap(),
+                branch: None,
+                internal_path: PathBuf::from("configs"),
+            },
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
+        )
+        .unwrap();
+        config_dir
+            .add_fallback_remote(&Url::from_file_path(&fallback_path).unwrap())
+            .unwrap();
+
+        // The mirror is behind origin, so its branches are kept apart and origin is not rewound
+        config_dir.fetch_only("fallback-1").unwrap();
+        assert_eq!(
+            config_dir
+                .find_branch_head("fallback-1", &default_branch, false)
+                .unwrap(),
+            first
+        );
+        assert_eq!(
+            config_dir
+                .find_branch_head("origin", &default_branch, false)
+                .unwrap(),
+            second
+        );
+    }
+
+    #[test]
+    fn test_fetch_hooks() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(source_dir.path()).unwrap();
+        let config = |version| {
+            format!(
+                r#"{{"Environment": "TEST", "ConfigData": {{"Version": {}}}}}"#,
+                version
+            )
+        };
+        let first = commit_configs(&repo, &[("configs/config.TEST.json", &config(1))]);
+        let default_branch = repo.head().unwrap().shorthand().unwrap().to_owned();
+
+        let mut config_dir = ConfigDir::new(
+            ConfigUrl::Git {
+                url: Url::from_file_path(source_dir.path()).unwrap(),
+                branch: None,
+                internal_path: PathBuf::from("configs"),
+            },
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
+        )
+        .unwrap();
+        let open = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let fetches = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let gate_open = open.clone();
+        let observed = fetches.clone();
+        config_dir.set_fetch_hooks(FetchHooks {
+            gate: Some(Arc::new(move |_| {
+                if gate_open.load(std::sync::atomic::Ordering::SeqCst) {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("Closed"))
+                }
+            })),
+            observer: Some(Arc::new(move |_, remote, _, success| {
+                observed.lock().unwrap().push((remote.to_owned(), success))
+            })),
+        });
+
+        // Lookups which would fetch are refused, and heads come from the refs already fetched
+        let second = commit_configs(&repo, &[("configs/config.TEST.json", &config(2))]);
+        assert!(config_dir.refresh(None, Some(&second), true).is_err());
+        assert!(config_dir
+            .find_at(None, &second, build_regex(".+").unwrap(), true)
+            .is_err());
+        assert_eq!(
+            config_dir
+                .find_branch_head("origin", &default_branch, true)
+                .unwrap(),
+            first
+        );
+        assert!(fetches.lock().unwrap().is_empty());
+
+        open.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(
+            config_dir.refresh(None, Some(&second), true).unwrap(),
+            second
+        );
+        assert_eq!(*fetches.lock().unwrap(), vec![("origin".to_string(), true)]);
+    }
+
+    #[test]
+    fn test_tags_and_refs() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(source_dir.path()).unwrap();
+        let config = |version| {
+            format!(
+                r#"{{"Environment": "TEST", "ConfigData": {{"Version": {}}}}}"#,
+                version
+            )
+        };
+        let first = commit_configs(&repo, &[("configs/config.TEST.json", &config(1))]);
+        let second = commit_configs(&repo, &[("configs/config.TEST.json", &config(2))]);
+        let default_branch = repo.head().unwrap().shorthand().unwrap().to_owned();
+        let signature = git2::Signature::now("hogan", "hogan@example.com").unwrap();
+        let first_commit = repo
+            .find_object(git2::Oid::from_str(&first).unwrap(), None)
+            .unwrap();
+        repo.tag("v1", &first_commit, &signature, "Release v1", false)
+            .unwrap();
+
+        let url = format!(
+            "file://{}/configs#v1",
+            source_dir.path().join(".git").display()
+        )
+        .parse::<ConfigUrl>()
+        .unwrap();
+        let filter = build_regex("config\\..+\\.json$").unwrap();
+        for native in [false, true] {
+            let config_dir = ConfigDir::new(
+                url.clone(),
+                &GitAuth::default(),
+                native,
+                native,
+                native,
+                NativeGit::default(),
+                CloneOptions::default(),
+                None,
+            )
+            .unwrap();
+            assert_eq!(config_dir.head_sha(), Some(first.as_str()));
+            assert_eq!(config_dir.find(filter.clone())[0].config_data["Version"], 1);
+        }
+
+        let config_dir = ConfigDir::new(
+            url,
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            CloneOptions::default(),
+            None,
+        )
+        .unwrap();
+        let find =
+            |reference: &str, refresh| config_dir.find_branch_head("origin", reference, refresh);
+        assert_eq!(find("v1", false).unwrap(), first);
+        assert_eq!(find("refs/tags/v1", false).unwrap(), first);
+        assert_eq!(find(&default_branch, false).unwrap(), second);
+        assert_eq!(
+            find(&format!("refs/heads/{}", default_branch), false).unwrap(),
+            second
+        );
+
+        // Tags of commits which are on no branch are fetched too
+        let tree = repo
+            .find_commit(git2::Oid::from_str(&second).unwrap())
+            .unwrap()
+            .tree()
+            .unwrap();
+        let parent = repo
+            .find_commit(git2::Oid::from_str(&second).unwrap())
+            .unwrap();
+        let third = repo
+            .commit(None, &signature, &signature, "Hotfix", &tree, &[&parent])
+            .unwrap();
+        repo.tag_lightweight("release/v3", &repo.find_object(third, None).unwrap(), false)
+            .unwrap();
+        assert!(matches!(
+            find("refs/tags/release/v3", false)
+                .unwrap_err()
+                .downcast_ref(),
+            Some(HoganError::UnknownBranch { .. })
+        ));
+        assert_eq!(
+            find("refs/tags/release/v3", true).unwrap(),
+            third.to_string()
+        );
+    }
+
+    #[test]
+    fn test_sparse_clone() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(source_dir.path()).unwrap();
+        let first = commit_configs(
+            &repo,
+            &[
+                (
+                    "configs/config.TEST.json",
+                    r#"{"Environment": "TEST", "ConfigData": {}}"#,
+                ),
+                ("other/large.bin", "unused"),
+            ],
+        );
+        let second = commit_configs(&repo, &[("other/large.bin", "still unused")]);
+        let url = ConfigUrl::Git {
+            url: Url::from_file_path(source_dir.path()).unwrap(),
+            branch: None,
+            internal_path: PathBuf::from("configs"),
+        };
+        let options = CloneOptions {
+            sparse: true,
+            ..Default::default()
+        };
+
+        let config_dir = ConfigDir::new(
+            url,
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            options,
+            None,
+        )
+        .unwrap();
+        let workdir = config_dir.directory().parent().unwrap();
+        assert!(config_dir.directory().join("config.TEST.json").is_file());
+        assert!(!workdir.join("other").exists());
+
+        assert_eq!(
+            config_dir.refresh(None, Some(&first), false).unwrap(),
+            first
+        );
+        assert!(!workdir.join("other").exists());
+        assert_eq!(
+            config_dir.refresh(None, Some(&second), false).unwrap(),
+            second
+        );
+    }
+
+    #[test]
+    fn test_partial_clone() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(source_dir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_bool("uploadpack.allowFilter", true).unwrap();
+        config
+            .set_bool("uploadpack.allowAnySHA1InWant", true)
+            .unwrap();
+        let first = commit_configs(
+            &repo,
+            &[(
+                "configs/config.TEST.json",
+                r#"{"Environment": "TEST", "ConfigData": {"Version": 1}}"#,
+            )],
+        );
+        let second = commit_configs(
+            &repo,
+            &[(
+                "configs/config.TEST.json",
+                r#"{"Environment": "TEST", "ConfigData": {"Version": 2}}"#,
+            )],
+        );
+        let url = ConfigUrl::Git {
+            url: Url::from_file_path(source_dir.path()).unwrap(),
+            branch: None,
+            internal_path: PathBuf::from("configs"),
+        };
+        let options = CloneOptions {
+            depth: Some(1),
+            blobless: true,
+            sparse: true,
+            ..Default::default()
+        };
+
+        // libgit2 can not make partial clones
+        assert!(ConfigDir::new(
+            url.clone(),
+            &GitAuth::default(),
+            false,
+            false,
+            false,
+            NativeGit::default(),
+            options.clone(),
+            None
+        )
+        .is_err());
+
+        let config_dir = ConfigDir::new(
+            url,
+            &GitAuth::default(),
+            true,
+            false,
+            true,
+            NativeGit::default(),
+            options,
+            None,
+        )
+        .unwrap();
+        let filter = build_regex("config\\..+\\.json$").unwrap();
+        assert_eq!(config_dir.head_sha(), Some(second.as_str()));
+        assert_eq!(config_dir.find(filter.clone())[0].config_data["Version"], 2);
+
+        // The first commit is beyond the depth of the clone, so it is fetched
+        assert!(config_dir.refresh(None, Some(&first), false).is_err());
+        assert_eq!(config_dir.refresh(None, Some(&first), true).unwrap(), first);
+        assert_eq!(config_dir.find(filter)[0].config_data["Version"], 1);
+    }
 }
@@ -6,10 +6,12 @@ pub enum HoganError {
     GitError { msg: String },
     #[error("The requested SHA {sha} was not found in the git repo")]
     UnknownSHA { sha: String },
-    #[error("The requested branch {branch} was not found in the git repo")]
+    #[error("The requested branch or tag {branch} was not found in the git repo")]
     UnknownBranch { branch: String },
     #[error("The requested environment {env} was not found in {sha}")]
     UnknownEnvironment { sha: String, env: String },
+    #[error("The config commit {sha} is not signed by an allowed signer. {msg}")]
+    UntrustedCommit { sha: String, msg: String },
     #[error("There was a problem with the provided template")]
     InvalidTemplate { msg: String, env: String },
     #[error("The request was malformed")]
@@ -1,41 +1,312 @@
+use crate::auth::{redact, redact_str, without_password, GitAuth};
 use crate::error::HoganError;
+use crate::ssh::HostKeyChecking;
 use anyhow::{Context, Result};
-use git2::build::RepoBuilder;
-use git2::{AutotagOption, Cred, FetchOptions, Reference, RemoteCallbacks, Repository, ResetType};
-use std::path::Path;
-use std::process::Command;
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{
+    AutotagOption, FetchOptions, ObjectType, Reference, Repository, ResetType,
+    SubmoduleUpdateOptions, TreeWalkMode, TreeWalkResult,
+};
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::str;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use url::Url;
 
-pub fn ext_clone(url: &Url, path: &Path) -> Result<()> {
-    info!("Cloning {:?} to {:?}", url, path);
-    let mut clone = Command::new("git")
-        .args(["clone", url.as_ref(), path.to_str().unwrap()])
-        .spawn()?;
-    let result = clone.wait()?;
-    info!("Clone output {}", result);
+/// Limits on how much of a config repository is cloned, and whether its submodules are too
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CloneOptions {
+    /// Only fetch this many commits of history
+    pub depth: Option<u32>,
+    /// Fetch file contents lazily, when they are checked out
+    pub blobless: bool,
+    /// Only check out the path of the configs within the repository
+    pub sparse: bool,
+    /// Recursively check out submodules along with each commit
+    pub submodules: bool,
+}
+
+impl CloneOptions {
+    /// Shallow and blobless clones leave out objects which libgit2 can not fetch later, so they
+    /// are cloned, fetched and checked out with the external git executable
+    pub fn is_partial(&self) -> bool {
+        self.depth.is_some() || self.blobless
+    }
+}
+
+/// Told the subcommand, duration and success of each native git command
+pub type GitTimer = Arc<dyn Fn(&str, Duration, bool) + Send + Sync>;
+
+/// Runs the external git executable
+#[derive(Clone, Default)]
+pub struct NativeGit {
+    /// Commands still running after this long are killed
+    pub timeout: Option<Duration>,
+    /// SSH key for git to authenticate with, through GIT_SSH_COMMAND
+    pub ssh_key: Option<PathBuf>,
+    /// Known hosts file for ssh to check host keys against, unless checking is off
+    pub known_hosts: Option<PathBuf>,
+    pub host_key_checking: HostKeyChecking,
+    pub timer: Option<GitTimer>,
+}
+
+impl fmt::Debug for NativeGit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeGit")
+            .field("timeout", &self.timeout)
+            .field("ssh_key", &self.ssh_key)
+            .field("known_hosts", &self.known_hosts)
+            .field("host_key_checking", &self.host_key_checking)
+            .field("timer", &self.timer.is_some())
+            .finish()
+    }
+}
+
+impl NativeGit {
+    // The ssh command for git to run, when it needs more than the default
+    fn ssh_command(&self) -> Option<String> {
+        let quote = |path: &Path| format!("'{}'", path.to_str().unwrap().replace('\'', "'\\''"));
+        let mut args = Vec::new();
+        if let Some(key) = &self.ssh_key {
+            args.push(format!("-i {}", quote(key)));
+        }
+        let strict = match self.host_key_checking {
+            HostKeyChecking::Off => None,
+            HostKeyChecking::Strict => Some("yes"),
+            HostKeyChecking::AcceptNew => Some("accept-new"),
+        };
+        if let (Some(known_hosts), Some(strict)) = (&self.known_hosts, strict) {
+            args.push(format!(
+                "-o UserKnownHostsFile={} -o StrictHostKeyChecking={}",
+                quote(known_hosts),
+                strict
+            ));
+        }
+        if args.is_empty() {
+            None
+        } else {
+            Some(format!("ssh {}", args.join(" ")))
+        }
+    }
+
+    /// Runs git, in a repository when a path is given, returning its trimmed output. Failures
+    /// and timeouts are errors with what git wrote to stderr
+    pub fn run(&self, path: Option<&Path>, args: &[&str]) -> Result<String> {
+        self.run_with_env(path, &[], args)
+    }
+
+    /// Runs git as `run` does, with extra environment variables
+    pub fn run_with_env(
+        &self,
+        path: Option<&Path>,
+        envs: &[(&str, &str)],
+        args: &[&str],
+    ) -> Result<String> {
+        // Urls may carry credentials
+        let description = format!(
+            "git {}",
+            args.iter()
+                .map(|arg| redact_str(arg))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        debug!("Running {}", description);
+
+        let mut command = Command::new("git");
+        if let Some(path) = path {
+            command.current_dir(path);
+        }
+        if let Some(ssh_command) = self.ssh_command() {
+            command.env("GIT_SSH_COMMAND", ssh_command);
+        }
+        command.envs(envs.iter().copied());
+        let start = Instant::now();
+        let mut child = command
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Unable to run {}", description))?;
+
+        // The output is read as it is written, so git never blocks on a full pipe
+        let stdout = read_in_background(child.stdout.take().unwrap());
+        let stderr = read_in_background(child.stderr.take().unwrap());
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if self
+                .timeout
+                .is_some_and(|timeout| start.elapsed() >= timeout)
+            {
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let elapsed = start.elapsed();
+        if let Some(timer) = &self.timer {
+            timer(
+                subcommand(args),
+                elapsed,
+                status.is_some_and(|status| status.success()),
+            );
+        }
+
+        let msg = match status {
+            Some(status) if status.success() => {
+                debug!("{} took {} ms", description, elapsed.as_millis());
+                return Ok(stdout.join().unwrap_or_default().trim().to_owned());
+            }
+            Some(status) => format!(
+                "{} failed with {}: {}",
+                description,
+                status,
+                stderr.join().unwrap_or_default().trim()
+            ),
+            // Processes git started may still hold the pipes open, so they are not waited on
+            None => format!("{} timed out after {} s", description, elapsed.as_secs()),
+        };
+        Err(HoganError::GitError { msg }.into())
+    }
+}
+
+// The git subcommand, after any `-c key=value` or `-C path` options which come before it
+fn subcommand<'a>(args: &[&'a str]) -> &'a str {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "-c" | "-C" => {
+                args.next();
+            }
+            arg => return arg,
+        }
+    }
+    ""
+}
+
+fn read_in_background<R: Read + Send + 'static>(mut reader: R) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = reader.read_to_end(&mut output);
+        String::from_utf8_lossy(&output).into_owned()
+    })
+}
+
+pub fn ext_clone(
+    url: &Url,
+    path: &Path,
+    options: &CloneOptions,
+    sparse_path: Option<&Path>,
+    native: &NativeGit,
+) -> Result<()> {
+    info!("Cloning {} to {:?}", redact(url), path);
+    let mut args = vec!["clone".to_owned()];
+    if let Some(depth) = options.depth {
+        // Keep every branch, so branch heads can still be looked up
+        args.push(format!("--depth={}", depth));
+        args.push("--no-single-branch".to_owned());
+    }
+    if options.blobless {
+        args.push("--filter=blob:none".to_owned());
+    }
+    if sparse_path.is_some() {
+        args.push("--no-checkout".to_owned());
+    }
+    args.push(url.to_string());
+    args.push(path.to_str().unwrap().to_owned());
+
+    native
+        .run(None, &args.iter().map(String::as_str).collect::<Vec<_>>())
+        .with_context(|| format!("Error cloning {}", redact(url)))?;
+
+    // git sparse-checkout turns on worktree config, which libgit2 can not open, so the sparse
+    // checkout is set up by hand
+    if let Some(sparse_path) = sparse_path {
+        ext_git(native, path, &["config", "core.sparseCheckout", "true"])
+            .context("Error enabling sparse checkout")?;
+        let pattern = format!("/{}/\n", sparse_path.to_str().unwrap().trim_matches('/'));
+        fs::write(path.join(".git/info/sparse-checkout"), pattern)
+            .context("Error setting the sparse checkout path")?;
+        ext_git(native, path, &["reset", "--hard", "HEAD"])
+            .context("Error checking out the clone")?;
+    }
     Ok(())
 }
 
+fn ext_git(native: &NativeGit, path: &Path, args: &[&str]) -> Result<String> {
+    native.run(Some(path), args)
+}
+
+/// Whether a string is a full or abbreviated hex object id, rather than a ref or anything git could
+/// read as an option
+pub fn is_object_id(s: &str) -> bool {
+    (4..=64).contains(&s.len()) && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Checks out a SHA with the external git executable, which keeps to the sparse checkout and
+/// fetches the contents a partial clone is missing. A missing SHA is fetched by itself from the
+/// remote when `fetch_sha` is set, as a shallow clone may not reach back to it after a fetch
+pub fn ext_reset(
+    path: &Path,
+    remote: &str,
+    sha: &str,
+    fetch_sha: bool,
+    native: &NativeGit,
+) -> Result<String> {
+    // The SHA may come from a request, so it must not be read by git as an option
+    if !is_object_id(sha) {
+        return Err(HoganError::UnknownSHA {
+            sha: sha.to_string(),
+        })
+        .context("Only hex SHAs can be checked out with git");
+    }
+
+    // git would fetch missing objects of a partial clone just to look them up, libgit2 does not
+    let repo = build_repo(path.to_str().unwrap())?;
+    let has_commit = || {
+        repo.revparse_single(sha)
+            .and_then(|object| object.peel_to_commit())
+            .is_ok()
+    };
+
+    if !has_commit() {
+        if !fetch_sha {
+            return Err(HoganError::UnknownSHA {
+                sha: sha.to_string(),
+            })
+            .context("Unknown SHA when checking out, may resolve next update");
+        }
+        if ext_git(native, path, &["fetch", "--end-of-options", remote, sha]).is_err() {
+            return Err(HoganError::UnknownSHA {
+                sha: sha.to_string(),
+            }
+            .into());
+        }
+    }
+
+    // Not every git version takes --end-of-options for reset, but the SHA is known to be hex
+    ext_git(native, path, &["reset", "--hard", sha, "--"])
+        .with_context(|| format!("Error detaching head to SHA {}", sha))?;
+    ext_git(native, path, &["rev-parse", "HEAD"])
+}
+
 pub fn clone(
     url: &Url,
     branch: Option<&str>,
     path: &Path,
-    ssh_key_path: Option<&Path>,
+    auth: Option<&GitAuth>,
+    sparse_path: Option<&Path>,
 ) -> Result<Repository> {
-    let mut callbacks = RemoteCallbacks::new();
-
-    if let Some(password) = url.password() {
-        debug!("Using password auth");
-        callbacks.credentials(move |_url, username_from_url, _allowed_types| {
-            Cred::userpass_plaintext(username_from_url.unwrap(), password)
-        });
-    } else if let Some(ssh_key_path) = ssh_key_path {
-        debug!("Using SSH auth");
-        callbacks.credentials(move |_url, username_from_url, _allowed_types| {
-            Cred::ssh_key(username_from_url.unwrap(), None, ssh_key_path, None)
-        });
-    }
+    let mut callbacks = auth
+        .map(|auth| auth.callbacks(Some(url)))
+        .unwrap_or_default();
 
     callbacks.transfer_progress(|stats| {
         if stats.received_objects() == stats.total_objects() {
@@ -70,47 +341,36 @@ pub fn clone(
 
     let mut fetch_options = FetchOptions::new();
     fetch_options.remote_callbacks(callbacks);
+    fetch_options.download_tags(AutotagOption::All);
 
     let mut repo_builder = RepoBuilder::new();
     repo_builder.fetch_options(fetch_options);
-
-    if let Some(branch) = branch {
-        debug!("Setting branch to {}", branch);
-        repo_builder.branch(branch);
+    if let Some(sparse_path) = sparse_path {
+        debug!("Only checking out {:?}", sparse_path);
+        let mut checkout = CheckoutBuilder::new();
+        checkout.path(sparse_path);
+        repo_builder.with_checkout(checkout);
     }
 
     info!("Cloning to {:?}", path);
-    repo_builder
-        .clone(url.as_str(), path)
+    let repo = repo_builder
+        .clone(without_password(url).as_str(), path)
         .map_err::<HoganError, _>(|e| e.into())
         .context(format!(
             "Error performing initial repository clone {}",
-            url.as_str()
-        ))
-}
-
-fn make_ssh_auth(ssh_key_path: &Path) -> RemoteCallbacks {
-    let mut callback = RemoteCallbacks::new();
-    callback.credentials(move |_url, username_from_url, _allowed_types| {
-        Cred::ssh_key(username_from_url.unwrap(), None, ssh_key_path, None)
-    });
-
-    callback
-}
+            redact(url)
+        ))?;
 
-fn make_password_auth(url: &Url) -> RemoteCallbacks {
-    if let Some(password) = url.password() {
-        let mut callback = RemoteCallbacks::new();
-        callback.credentials(move |_url, username_from_url, _allowed_type| {
-            Cred::userpass_plaintext(username_from_url.unwrap(), password)
-        });
-        callback
-    } else {
-        RemoteCallbacks::new()
+    // libgit2 can only clone branches, so tags and other refs are checked out after the clone
+    if let Some(branch) = branch {
+        debug!("Checking out {}", branch);
+        let sha = find_branch_head(&repo, "origin", branch)?;
+        detach_head(&repo, &sha, sparse_path)?;
     }
+    Ok(repo)
 }
 
-fn detach_head(repo: &Repository, sha: &str) -> Result<()> {
+fn detach_head(repo: &Repository, sha: &str, sparse_path: Option<&Path>) -> Result<()> {
     let revspec = repo
         .revparse_single(sha)
         .map_err(|_| HoganError::UnknownSHA {
@@ -118,51 +378,172 @@ fn detach_head(repo: &Repository, sha: &str) -> Result<()> {
         })?;
 
     debug!("Found revision {}. Switching repo head.", sha);
-    repo.reset(&revspec, ResetType::Hard, None)
+    let mut checkout = CheckoutBuilder::new();
+    if let Some(sparse_path) = sparse_path {
+        checkout.path(sparse_path);
+    }
+    repo.reset(&revspec, ResetType::Hard, Some(&mut checkout))
         .map_err::<HoganError, _>(|e| e.into())
         .context(format!("Error detaching head to SHA {}", sha))
 }
 
-pub fn ext_fetch(path: &Path, remote: &str) -> Result<()> {
+pub fn ext_fetch(path: &Path, remote: &str, native: &NativeGit) -> Result<()> {
     debug!("Fetching {}", remote);
-    let mut fetch_cmd = Command::new("git")
-        .current_dir(path.to_str().unwrap())
-        .args(["fetch", "--prune", remote])
-        .spawn()?;
+    ext_git(
+        native,
+        path,
+        &["fetch", "--prune", "--tags", "--end-of-options", remote],
+    )?;
+    Ok(())
+}
 
-    fetch_cmd.wait()?;
+/// Recursively initializes and checks out submodules with the external git executable, only
+/// those within the sparse checkout path when there is one
+pub fn ext_update_submodules(
+    path: &Path,
+    sparse_path: Option<&Path>,
+    native: &NativeGit,
+) -> Result<()> {
+    debug!("Updating submodules");
+    let mut args = vec!["submodule", "update", "--init", "--recursive"];
+    if let Some(sparse_path) = sparse_path {
+        args.push("--");
+        args.push(sparse_path.to_str().unwrap());
+    }
+    ext_git(native, path, &args).context("Error updating submodules")?;
     Ok(())
 }
 
-pub fn ext_maintenance(path: &Path) -> Result<()> {
+/// Recursively initializes and checks out submodules, only those within the sparse checkout path
+/// when there is one. Relative submodule urls are resolved against the url of the repository, as
+/// its remote may be a local mirror
+pub fn update_submodules(
+    repo: &Repository,
+    url: Option<&Url>,
+    auth: Option<&GitAuth>,
+    sparse_path: Option<&Path>,
+) -> Result<()> {
+    let submodules = repo
+        .submodules()
+        .map_err::<HoganError, _>(|e| e.into())
+        .context("Error listing submodules")?;
+
+    for mut submodule in submodules {
+        if sparse_path.is_some_and(|sparse_path| !submodule.path().starts_with(sparse_path)) {
+            continue;
+        }
+        let name = submodule.name().unwrap_or_default().to_owned();
+
+        let relative_url = submodule
+            .url()
+            .filter(|url| url.starts_with("./") || url.starts_with("../"))
+            .map(|url| url.to_owned());
+        if let (Some(url), Some(relative_url)) = (url, relative_url) {
+            // Relative urls are relative to the repository as a directory
+            let base = Url::parse(&format!(
+                "{}/",
+                without_password(url).as_str().trim_end_matches('/')
+            ))?;
+            let submodule_url = base.join(&relative_url)?;
+            debug!("Resolved submodule {} to {}", name, redact(&submodule_url));
+            // Initializing a submodule keeps a url already in the repository config
+            repo.config()
+                .and_then(|mut config| {
+                    config.set_str(&format!("submodule.{}.url", name), submodule_url.as_str())
+                })
+                .map_err::<HoganError, _>(|e| e.into())
+                .context(format!("Error setting the url of submodule {}", name))?;
+            submodule = repo
+                .find_submodule(&name)
+                .map_err::<HoganError, _>(|e| e.into())
+                .context(format!("Error finding submodule {}", name))?;
+        }
+
+        debug!("Updating submodule {}", name);
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(auth.map(|auth| auth.callbacks(None)).unwrap_or_default());
+        let mut update_options = SubmoduleUpdateOptions::new();
+        update_options.fetch(fetch_options);
+        submodule
+            .update(true, Some(&mut update_options))
+            .map_err::<HoganError, _>(|e| e.into())
+            .context(format!("Error updating submodule {}", name))?;
+
+        let submodule_repo = submodule
+            .open()
+            .map_err::<HoganError, _>(|e| e.into())
+            .context(format!("Error opening submodule {}", name))?;
+        update_submodules(&submodule_repo, None, auth, None)?;
+    }
+    Ok(())
+}
+
+pub fn ext_maintenance(path: &Path, native: &NativeGit) -> Result<()> {
     info!("Performing maintenance");
-    let mut maintenance_cmd = Command::new("git")
-        .current_dir(path.to_str().unwrap())
-        .args(["maintenance", "run", "--auto"])
-        .spawn()?;
-    maintenance_cmd.wait()?;
-
-    let mut prune_cmd = Command::new("git")
-        .current_dir(path.to_str().unwrap())
-        .args(["remote", "prune", "origin"])
-        .spawn()?;
-    prune_cmd.wait()?;
+    ext_git(native, path, &["maintenance", "run", "--auto"])?;
+    ext_git(native, path, &["remote", "prune", "origin"])?;
+    Ok(())
+}
+
+/// Creates or incrementally updates a bare mirror of the branches of a repository. When offline
+/// an existing mirror is used without contacting the remote
+pub fn mirror(url: &Url, path: &Path, auth: Option<&GitAuth>, offline: bool) -> Result<()> {
+    let repo = match Repository::open_bare(path) {
+        Ok(repo) => repo,
+        Err(_) if offline => {
+            return Err(HoganError::GitError {
+                msg: format!("No mirror of {} is cached in {:?}", redact(url), path),
+            })
+            .context("A mirror must be created before running offline");
+        }
+        Err(_) => {
+            info!("Creating mirror of {} in {:?}", redact(url), path);
+            let repo = Repository::init_bare(path)
+                .map_err::<HoganError, _>(|e| e.into())
+                .context(format!("Error creating mirror in {:?}", path))?;
+            repo.remote_with_fetch(
+                "origin",
+                without_password(url).as_str(),
+                "+refs/heads/*:refs/heads/*",
+            )
+            .map_err::<HoganError, _>(|e| e.into())
+            .context("Error adding the mirror remote")?;
+            repo
+        }
+    };
+
+    if offline {
+        info!("Using mirror in {:?} without fetching", path);
+        return Ok(());
+    }
+
+    info!("Updating mirror in {:?}", path);
+    // Clones of the mirror check out its HEAD, so it follows the default branch of the remote
+    if let Some(head) = fetch_default_branch(&repo, "origin", auth, Some(url))? {
+        repo.set_head(&head)
+            .map_err::<HoganError, _>(|e| e.into())
+            .context(format!("Error setting the mirror head to {}", head))?;
+    }
     Ok(())
 }
 
 pub fn fetch(
     repo: &Repository,
     remote: &str,
-    ssh_key_path: Option<&Path>,
+    auth: Option<&GitAuth>,
     url: Option<&Url>,
 ) -> Result<()> {
-    let mut cb = if let Some(s) = ssh_key_path {
-        make_ssh_auth(s)
-    } else if let Some(u) = url {
-        make_password_auth(u)
-    } else {
-        RemoteCallbacks::default()
-    };
+    fetch_default_branch(repo, remote, auth, url).map(|_| ())
+}
+
+// Fetches from the remote, returning the name of its default branch if it has one
+fn fetch_default_branch(
+    repo: &Repository,
+    remote: &str,
+    auth: Option<&GitAuth>,
+    url: Option<&Url>,
+) -> Result<Option<String>> {
+    let mut cb = auth.map(|auth| auth.callbacks(url)).unwrap_or_default();
     let mut remote = repo.find_remote(remote).or_else(|_| {
         repo.remote_anonymous(remote)
             .map_err::<HoganError, _>(|e| e.into())
@@ -206,10 +587,16 @@ pub fn fetch(
 
     let mut fo = FetchOptions::new();
     fo.remote_callbacks(cb);
+    // Configs are released by tagging, so every tag is fetched rather than those on branches
+    fo.download_tags(AutotagOption::All);
     remote
         .download(&Vec::<String>::new(), Some(&mut fo))
         .map_err::<HoganError, _>(|e| e.into())
         .context("Error fetching remote update")?;
+    let default_branch = remote
+        .default_branch()
+        .ok()
+        .and_then(|branch| branch.as_str().map(|b| b.to_owned()));
 
     remote
         .disconnect()
@@ -217,34 +604,78 @@ pub fn fetch(
         .context("Error disconnecting from remote")?;
 
     remote
-        .update_tips(None, true, AutotagOption::Unspecified, None)
+        .update_tips(None, true, AutotagOption::All, None)
         .map_err::<HoganError, _>(|e| e.into())
         .context("Error updating tips of git repository")?;
 
+    Ok(default_branch)
+}
+
+/// Moves the branches of one remote forward to those fetched from another. A branch is only moved
+/// when the other remote's head is a descendant of it, so a remote which is behind never rewinds it
+pub fn fast_forward_remote(repo: &Repository, from: &str, to: &str) -> Result<()> {
+    let prefix = format!("refs/remotes/{}/", from);
+    let references = repo
+        .references_glob(&format!("{}*", prefix))
+        .map_err::<HoganError, _>(|e| e.into())
+        .context(format!("Listing the branches of {}", from))?;
+    for reference in references.flatten() {
+        let (name, target) = match (reference.name(), reference.target()) {
+            (Some(name), Some(target)) => (name, target),
+            _ => continue,
+        };
+        let branch = &name[prefix.len()..];
+        if branch == "HEAD" {
+            continue;
+        }
+        let to_name = format!("refs/remotes/{}/{}", to, branch);
+        if let Ok(current) = repo.refname_to_id(&to_name) {
+            if current == target {
+                continue;
+            }
+            if !repo.graph_descendant_of(target, current).unwrap_or(false) {
+                debug!(
+                    "Not moving {} from {} to {} from {}, which is not ahead of it",
+                    to_name, current, target, from
+                );
+                continue;
+            }
+        }
+        repo.reference(
+            &to_name,
+            target,
+            true,
+            &format!("Fast-forward from {}", from),
+        )
+        .map_err::<HoganError, _>(|e| e.into())
+        .context(format!("Updating {}", to_name))?;
+    }
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn reset(
     repo: &Repository,
     remote: &str,
-    ssh_key_path: Option<&Path>,
+    auth: Option<&GitAuth>,
     url: Option<&Url>,
     sha: Option<&str>,
     force_refresh: bool,
     allow_fetch: bool,
+    sparse_path: Option<&Path>,
 ) -> Result<String> {
     if force_refresh && allow_fetch {
-        fetch(repo, remote, ssh_key_path, url)?;
+        fetch(repo, remote, auth, url)?;
     };
 
     if let Some(sha) = sha {
-        match detach_head(repo, sha) {
+        match detach_head(repo, sha, sparse_path) {
             Ok(_) => {}
             Err(_) => {
                 if allow_fetch {
                     info!("Couldn't find {}. Trying to refreshing repo", sha);
-                    fetch(repo, remote, ssh_key_path, url)?;
-                    match detach_head(repo, sha) {
+                    fetch(repo, remote, auth, url)?;
+                    match detach_head(repo, sha, sparse_path) {
                         Ok(_) => {}
                         Err(e) => {
                             warn!("Unable to find ref {}: {:?}", sha, e);
@@ -264,6 +695,65 @@ pub fn reset(
     get_head_sha(repo)
 }
 
+/// Files read from a git tree, as paths relative to the tree with their contents
+pub type TreeFiles = Vec<(PathBuf, Vec<u8>)>;
+
+/// Reads the files under a directory of a commit from the object database, leaving the working
+/// directory untouched. Only the files `keep` is true for are read. Returns the full SHA of the
+/// commit with the paths relative to the directory
+pub fn read_tree(
+    repo: &Repository,
+    sha: &str,
+    path: &Path,
+    keep: impl Fn(&Path) -> bool,
+) -> Result<(String, TreeFiles)> {
+    let commit = repo
+        .revparse_single(sha)
+        .and_then(|object| object.peel_to_commit())
+        .map_err(|_| HoganError::UnknownSHA {
+            sha: sha.to_owned(),
+        })?;
+    let mut tree = commit
+        .tree()
+        .map_err::<HoganError, _>(|e| e.into())
+        .context(format!("Error reading the tree of {}", sha))?;
+    if path.components().next().is_some() {
+        tree = tree
+            .get_path(path)
+            .and_then(|entry| entry.to_object(repo))
+            .and_then(|object| object.peel_to_tree())
+            .map_err::<HoganError, _>(|e| e.into())
+            .context(format!("Unable to find {:?} in {}", path, sha))?;
+    }
+
+    let mut blobs = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            if let Some(name) = entry.name() {
+                let path = Path::new(root).join(name);
+                if keep(&path) {
+                    blobs.push((path, entry.id()));
+                }
+            }
+        }
+        TreeWalkResult::Ok
+    })
+    .map_err::<HoganError, _>(|e| e.into())
+    .context(format!("Error walking the tree of {}", sha))?;
+
+    let files = blobs
+        .into_iter()
+        .map(|(path, id)| {
+            let blob = repo
+                .find_blob(id)
+                .map_err::<HoganError, _>(|e| e.into())
+                .context(format!("Error reading {:?} in {}", path, sha))?;
+            Ok((path, blob.content().to_vec()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((commit.id().to_string(), files))
+}
+
 pub fn build_repo(path: &str) -> Result<Repository> {
     Repository::discover(path).map_err(|e| e.into())
 }
@@ -288,12 +778,115 @@ pub fn get_head_sha(repo: &Repository) -> Result<String> {
     find_ref_sha(&head).context("Unable to find the head SHA")
 }
 
-pub fn find_branch_head(repo: &Repository, branch: &str) -> Result<String> {
-    let branch_ref = repo
-        .resolve_reference_from_short_name(branch)
-        .map_err(|_| HoganError::UnknownBranch {
+/// Finds the commit a branch of the remote, a tag or a fully qualified ref points to. Branches are
+/// looked up before tags, so a tag with the name of a branch needs to be given as `refs/tags/...`
+pub fn find_branch_head(repo: &Repository, remote: &str, branch: &str) -> Result<String> {
+    let candidates = match branch.strip_prefix("refs/heads/") {
+        // Branches of the remote are only kept as remote tracking branches
+        Some(name) => vec![format!("refs/remotes/{}/{}", remote, name)],
+        None if branch.starts_with("refs/") => vec![branch.to_owned()],
+        None => vec![
+            format!("refs/remotes/{}/{}", remote, branch),
+            format!("refs/tags/{}", branch),
+        ],
+    };
+    let commit = candidates
+        .iter()
+        .find_map(|name| {
+            repo.find_reference(name)
+                .and_then(|reference| reference.peel_to_commit())
+                .ok()
+        })
+        .ok_or_else(|| HoganError::UnknownBranch {
             branch: branch.to_owned(),
         })
-        .context(format!("Unable to find branch {}", branch))?;
-    find_ref_sha(&branch_ref).context(format!("Unable to find the head SHA of branch {}", branch))
+        .context(format!("Unable to find branch or tag {}", branch))?;
+    Ok(commit.id().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_native_git_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let native = NativeGit::default();
+
+        let error = native
+            .run(Some(dir.path()), &["rev-parse", "HEAD"])
+            .unwrap_err();
+        let msg = match error.downcast_ref() {
+            Some(HoganError::GitError { msg }) => msg.clone(),
+            _ => panic!("Unexpected error {:?}", error),
+        };
+        assert!(msg.starts_with("git rev-parse HEAD failed"));
+        assert!(msg.contains("not a git repository"));
+    }
+
+    #[test]
+    fn test_ssh_command() {
+        let mut native = NativeGit::default();
+        assert_eq!(native.ssh_command(), None);
+
+        native.known_hosts = Some(PathBuf::from("/home/hogan/.ssh/known_hosts"));
+        assert_eq!(native.ssh_command(), None);
+
+        native.ssh_key = Some(PathBuf::from("/home/hogan/.ssh/id_rsa"));
+        native.host_key_checking = HostKeyChecking::Strict;
+        assert_eq!(
+            native.ssh_command().unwrap(),
+            "ssh -i '/home/hogan/.ssh/id_rsa' -o UserKnownHostsFile='/home/hogan/.ssh/known_hosts' -o StrictHostKeyChecking=yes"
+        );
+    }
+
+    #[test]
+    fn test_ext_reset_rejects_options() {
+        let dir = tempfile::tempdir().unwrap();
+        Repository::init(dir.path()).unwrap();
+        let marker = dir.path().join("PWNED");
+        let sha = format!("--upload-pack=touch {}; git-upload-pack", marker.display());
+
+        let error = ext_reset(dir.path(), "origin", &sha, true, &NativeGit::default()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(HoganError::UnknownSHA { .. })
+        ));
+        assert!(!marker.exists());
+
+        assert!(is_object_id("1faf628"));
+        assert!(is_object_id(&"a".repeat(40)));
+        assert!(!is_object_id("origin/master"));
+        assert!(!is_object_id("abc"));
+    }
+
+    #[test]
+    fn test_native_git_timeout() {
+        let timings = Arc::new(Mutex::new(Vec::new()));
+        let recorded = timings.clone();
+        let native = NativeGit {
+            timeout: Some(Duration::from_millis(200)),
+            timer: Some(Arc::new(move |command, _, success| {
+                recorded.lock().unwrap().push((command.to_owned(), success))
+            })),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let error = native
+            .run(None, &["-c", "alias.slow=!sleep 5", "slow"])
+            .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(error.to_string().contains("timed out"));
+
+        assert_eq!(
+            native.run(None, &["--version"]).unwrap()[..11],
+            *"git version"
+        );
+        assert_eq!(
+            *timings.lock().unwrap(),
+            vec![("slow".to_owned(), false), ("--version".to_owned(), true)]
+        );
+    }
 }
@@ -3,21 +3,51 @@ extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod analysis;
+pub mod auth;
 pub mod config;
 pub mod error;
 pub mod git;
+pub mod lock;
+pub mod manifest;
+pub mod signature;
+pub mod ssh;
 pub mod template;
 pub mod transform;
 
+use ignore::WalkBuilder;
 use regex::Regex;
 use std::path::{Path, PathBuf};
-use walkdir::{DirEntry, WalkDir};
 
-pub fn find_file_paths(path: &Path, filter: Regex) -> Box<dyn Iterator<Item = PathBuf>> {
-    fn match_filter(entry: &DirEntry, filter: &Regex) -> bool {
-        entry
-            .file_name()
-            .to_str()
+/// Paths matched by this file, in gitignore syntax, are skipped when finding templates and configs
+pub const IGNORE_FILE: &str = ".hoganignore";
+
+/// Walks the files under a directory, skipping `.git` and anything ignored by a `.hoganignore`,
+/// and by the project's `.gitignore` files when `gitignore` is set
+pub fn walk_files(path: &Path, gitignore: bool) -> Box<dyn Iterator<Item = PathBuf>> {
+    Box::new(
+        WalkBuilder::new(path)
+            .standard_filters(false)
+            .parents(true)
+            .add_custom_ignore_filename(IGNORE_FILE)
+            .git_ignore(gitignore)
+            .git_exclude(gitignore)
+            .filter_entry(|e| e.file_name() != ".git")
+            .build()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false))
+            .map(|e| e.into_path()),
+    )
+}
+
+pub fn find_file_paths(
+    path: &Path,
+    filter: Regex,
+    gitignore: bool,
+) -> Box<dyn Iterator<Item = PathBuf>> {
+    fn match_filter(path: &Path, filter: &Regex) -> bool {
+        path.file_name()
+            .and_then(|s| s.to_str())
             .map(|s| filter.is_match(s))
             .unwrap_or(false)
     }
@@ -25,12 +55,5 @@ pub fn find_file_paths(path: &Path, filter: Regex) -> Box<dyn Iterator<Item = Pa
     println!("Finding Files: {:?}", path);
     println!("regex: /{}/", filter);
 
-    Box::new(
-        WalkDir::new(path)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| e.file_type().is_file())
-            .filter(move |e| match_filter(e, &filter))
-            .map(|e| e.path().to_path_buf()),
-    )
+    Box::new(walk_files(path, gitignore).filter(move |p| match_filter(p, &filter)))
 }
@@ -0,0 +1,70 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+pub const LOCK_FILE_NAME: &str = "hogan.lock";
+
+/// Pins the git config repository used by transform to a single SHA
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct Lock {
+    pub configs: String,
+    /// The branch, tag or ref the SHA was the head of, when the configs URL named one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    pub sha: String,
+}
+
+impl Lock {
+    pub fn path(templates_path: &Path) -> PathBuf {
+        templates_path.join(LOCK_FILE_NAME)
+    }
+
+    /// Loads the lock from the templates directory, if there is one
+    pub fn load(templates_path: &Path) -> Result<Option<Lock>> {
+        let path = Lock::path(templates_path);
+        if !path.is_file() {
+            debug!("No lock found at {:?}", path);
+            return Ok(None);
+        }
+
+        let file = File::open(&path).with_context(|| format!("Opening lock {:?}", path))?;
+        serde_json::from_reader(file)
+            .map(Some)
+            .with_context(|| format!("Parsing lock {:?}", path))
+    }
+
+    pub fn save(&self, templates_path: &Path) -> Result<()> {
+        let path = Lock::path(templates_path);
+        let file = File::create(&path).with_context(|| format!("Creating lock {:?}", path))?;
+        serde_json::to_writer_pretty(file, self).with_context(|| format!("Writing lock {:?}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        assert_eq!(Lock::load(temp_dir.path()).unwrap(), None);
+
+        let lock = Lock {
+            configs: "ssh://git@github.com/cvent/hogan.git".to_string(),
+            branch: Some("main".to_string()),
+            sha: "9d1a0e7ba3cd9d6fb0d0d1d8e4e6e2dc5ad1e8ea".to_string(),
+        };
+        lock.save(temp_dir.path()).unwrap();
+
+        assert_eq!(Lock::load(temp_dir.path()).unwrap(), Some(lock));
+
+        // Locks written before branches were recorded have none
+        std::fs::write(
+            Lock::path(temp_dir.path()),
+            r#"{"Configs": "ssh://git@github.com/cvent/hogan.git", "Sha": "9d1a0e7b"}"#,
+        )
+        .unwrap();
+        assert_eq!(Lock::load(temp_dir.path()).unwrap().unwrap().branch, None);
+    }
+}
@@ -4,7 +4,10 @@ extern crate log;
 extern crate lazy_static;
 
 use crate::app::cli;
-use crate::app::config::{App, AppCommand};
+use crate::app::config::{read_secret, App, AppCommand};
+use crate::app::coverage;
+use crate::app::lock;
+use crate::app::project;
 use crate::app::server;
 use anyhow::{Context, Result};
 
@@ -14,7 +17,8 @@ mod app;
 mod storage;
 
 fn main() -> Result<()> {
-    let opt = App::from_args();
+    let matches = App::clap().get_matches();
+    let mut opt = App::from_clap(&matches);
 
     stderrlog::new()
         .module(module_path!())
@@ -23,22 +27,69 @@ fn main() -> Result<()> {
         .init()
         .with_context(|| "Error initializing logging")?;
 
+    project::apply_project(&mut opt, &matches)?;
+
     match opt.cmd {
         AppCommand::Transform {
             templates_path,
             environments_regex,
             templates_regex,
             common,
+            mirror,
+            gitignore,
             ignore_existing,
+            prune,
+            dry_run,
+            all_errors,
+            jobs,
+            update,
+            ..
         } => {
             cli::cli(
                 templates_path,
                 environments_regex,
                 templates_regex,
                 common,
+                mirror,
+                gitignore,
                 ignore_existing,
+                prune,
+                dry_run,
+                all_errors,
+                jobs,
+                update,
+            )?;
+        }
+        AppCommand::Coverage {
+            common,
+            mirror,
+            environments_regex,
+            templates_path,
+            templates_regex,
+            gitignore,
+            list_keys,
+            check,
+            ..
+        } => {
+            coverage::coverage(
+                templates_path,
+                environments_regex,
+                templates_regex,
+                common,
+                mirror,
+                gitignore,
+                list_keys,
+                check,
             )?;
         }
+        AppCommand::Lock {
+            common,
+            mirror,
+            templates_path,
+            branch,
+        } => {
+            lock::lock(templates_path, common, mirror, branch)?;
+        }
         AppCommand::Server {
             common,
             port,
@@ -48,7 +99,16 @@ fn main() -> Result<()> {
             environment_pattern,
             db_path,
             fetch_poller,
+            fetch_max_backoff,
+            maintenance_interval,
             allow_fetch,
+            tree_reads,
+            fallback_remotes,
+            webhook_secret,
+            webhook_secret_file,
+            webhook_debounce,
+            head_timeout,
+            head_cache_ttl,
             db_max_age,
             cache_size,
         } => {
@@ -62,7 +122,15 @@ fn main() -> Result<()> {
                 db_path,
                 cache_size,
                 fetch_poller,
+                fetch_max_backoff,
+                maintenance_interval,
                 allow_fetch,
+                tree_reads,
+                fallback_remotes,
+                read_secret(&webhook_secret, &webhook_secret_file)?,
+                webhook_debounce,
+                head_timeout,
+                head_cache_ttl,
                 db_max_age,
             )?;
         }
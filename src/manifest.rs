@@ -0,0 +1,212 @@
+use crate::error::HoganError;
+use anyhow::{Context, Result};
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+
+pub const MANIFEST_FILE_NAME: &str = ".hogan-manifest.json";
+
+/// A single file generated by hogan. Paths are relative to the templates directory
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "PascalCase")]
+pub struct ManifestEntry {
+    pub template: PathBuf,
+    pub environment: String,
+    pub output: PathBuf,
+}
+
+/// Tracks the files generated by previous transforms so stale outputs can be pruned
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Manifest {
+    entries: BTreeSet<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn path(templates_path: &Path) -> PathBuf {
+        templates_path.join(MANIFEST_FILE_NAME)
+    }
+
+    /// Loads the manifest from the templates directory. A missing manifest is treated as empty
+    pub fn load(templates_path: &Path) -> Result<Manifest> {
+        let path = Manifest::path(templates_path);
+        if !path.is_file() {
+            debug!("No manifest found at {:?}", path);
+            return Ok(Manifest::default());
+        }
+
+        let file = File::open(&path).with_context(|| format!("Opening manifest {:?}", path))?;
+        serde_json::from_reader(file).with_context(|| format!("Parsing manifest {:?}", path))
+    }
+
+    pub fn save(&self, templates_path: &Path) -> Result<()> {
+        let path = Manifest::path(templates_path);
+        let file = File::create(&path).with_context(|| format!("Creating manifest {:?}", path))?;
+        serde_json::to_writer_pretty(file, self)
+            .with_context(|| format!("Writing manifest {:?}", path))
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.iter()
+    }
+
+    pub fn insert(&mut self, entry: ManifestEntry) {
+        self.entries.insert(entry);
+    }
+
+    /// Finds the entries whose template no longer exists, or whose environment is not in the
+    /// provided list of known environments. Outputs which were rendered during the current run
+    /// are never considered stale
+    pub fn stale(
+        &self,
+        templates_path: &Path,
+        environments: &HashSet<String>,
+        rendered: &HashSet<PathBuf>,
+    ) -> Vec<ManifestEntry> {
+        self.entries
+            .iter()
+            .filter(|e| !rendered.contains(&e.output))
+            .filter(|e| {
+                !environments.contains(&e.environment)
+                    || !templates_path.join(&e.template).is_file()
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Removes the stale entries from the manifest and deletes their outputs from disk. Nothing is
+    /// removed if any output lies outside the templates directory
+    pub fn prune(&mut self, templates_path: &Path, stale: &[ManifestEntry]) -> Result<()> {
+        if let Some(entry) = stale.iter().find(|e| {
+            !e.output
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        }) {
+            return Err(HoganError::InvalidConfiguration {
+                param: MANIFEST_FILE_NAME.to_string(),
+                msg: format!(
+                    "The output {:?} is outside the templates directory",
+                    entry.output
+                ),
+            }
+            .into());
+        }
+
+        for entry in stale {
+            let output = templates_path.join(&entry.output);
+            if output.is_file() {
+                fs::remove_file(&output)
+                    .with_context(|| format!("Removing stale output {:?}", output))?;
+            }
+            self.entries.remove(entry);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(template: &str, environment: &str, output: &str) -> ManifestEntry {
+        ManifestEntry {
+            template: PathBuf::from(template),
+            environment: environment.to_string(),
+            output: PathBuf::from(output),
+        }
+    }
+
+    #[test]
+    fn test_stale_entries() {
+        let templates_path = Path::new("tests/fixtures/projects/templates");
+        let mut manifest = Manifest::default();
+        manifest.insert(entry(
+            "project-1/Web.template.config",
+            "TEST",
+            "project-1/Web.TEST.config",
+        ));
+        manifest.insert(entry(
+            "project-1/Web.template.config",
+            "OLDENV",
+            "project-1/Web.OLDENV.config",
+        ));
+        manifest.insert(entry(
+            "project-9/Web.template.config",
+            "TEST",
+            "project-9/Web.TEST.config",
+        ));
+
+        let environments = ["TEST".to_string()].iter().cloned().collect();
+        let stale = manifest.stale(templates_path, &environments, &HashSet::new());
+
+        assert_eq!(
+            stale,
+            vec![
+                entry(
+                    "project-1/Web.template.config",
+                    "OLDENV",
+                    "project-1/Web.OLDENV.config"
+                ),
+                entry(
+                    "project-9/Web.template.config",
+                    "TEST",
+                    "project-9/Web.TEST.config"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rendered_entries_are_not_stale() {
+        let templates_path = Path::new("tests/fixtures/projects/templates");
+        let mut manifest = Manifest::default();
+        manifest.insert(entry(
+            "project-1/Web.template.config",
+            "OLDENV",
+            "project-1/Web.OLDENV.config",
+        ));
+
+        let rendered = [PathBuf::from("project-1/Web.OLDENV.config")]
+            .iter()
+            .cloned()
+            .collect();
+        let stale = manifest.stale(templates_path, &HashSet::new(), &rendered);
+
+        assert!(stale.is_empty());
+    }
+
+    #[test]
+    fn test_prune_rejects_outputs_outside_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let templates_path = dir.path().join("templates");
+        fs::create_dir(&templates_path).unwrap();
+        fs::write(dir.path().join("outside.config"), "keep").unwrap();
+        fs::write(templates_path.join("Web.OLDENV.config"), "stale").unwrap();
+
+        let mut manifest = Manifest::default();
+        let stale = vec![
+            entry("Web.template.config", "OLDENV", "Web.OLDENV.config"),
+            entry("Web.template.config", "OLDENV", "../outside.config"),
+        ];
+        for entry in &stale {
+            manifest.insert(entry.clone());
+        }
+
+        assert!(manifest.prune(&templates_path, &stale).is_err());
+        assert!(dir.path().join("outside.config").is_file());
+        assert!(templates_path.join("Web.OLDENV.config").is_file());
+        assert_eq!(manifest.entries().count(), 2);
+
+        let absolute = entry(
+            "Web.template.config",
+            "OLDENV",
+            &dir.path().join("outside.config").to_string_lossy(),
+        );
+        assert!(manifest.prune(&templates_path, &[absolute]).is_err());
+        assert!(dir.path().join("outside.config").is_file());
+
+        manifest.prune(&templates_path, &stale[..1]).unwrap();
+        assert!(!templates_path.join("Web.OLDENV.config").exists());
+        assert_eq!(manifest.entries().count(), 1);
+    }
+}
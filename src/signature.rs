@@ -0,0 +1,165 @@
+use crate::error::HoganError;
+use crate::git::NativeGit;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// The keys config commits must be signed with. Signatures are checked by the external git
+/// executable, which uses gpg or ssh-keygen depending on the kind of signature
+#[derive(Debug, Clone, Default)]
+pub struct AllowedSigners {
+    /// An SSH allowed signers file, as written for `gpg.ssh.allowedSignersFile`
+    pub ssh: Option<PathBuf>,
+    /// A GnuPG home directory holding the public keys of the allowed signers
+    pub gpg: Option<PathBuf>,
+}
+
+impl AllowedSigners {
+    /// Checks that a commit of the repository has a good signature from an allowed signer
+    pub fn verify_commit(&self, native: &NativeGit, repo_path: &Path, sha: &str) -> Result<()> {
+        // Any keys in the user's own git config and keyring must not be trusted, so both are
+        // always set. git rejects SSH signatures when there is no allowed signers file
+        let ssh = match &self.ssh {
+            Some(path) => canonical(path)?,
+            None => String::new(),
+        };
+
+        let _empty_keyring;
+        let gpg = match &self.gpg {
+            Some(path) => canonical(path)?,
+            None => {
+                _empty_keyring = TempDir::new().context("Error creating an empty GnuPG home")?;
+                _empty_keyring.path().to_str().unwrap().to_owned()
+            }
+        };
+
+        let envs = [
+            ("GIT_CONFIG_COUNT", "1"),
+            ("GIT_CONFIG_KEY_0", "gpg.ssh.allowedSignersFile"),
+            ("GIT_CONFIG_VALUE_0", ssh.as_str()),
+            ("GNUPGHOME", gpg.as_str()),
+        ];
+        let commit = format!("{}^{{commit}}", sha);
+        match native.run_with_env(Some(repo_path), &envs, &["verify-commit", &commit]) {
+            Ok(_) => {
+                debug!("Verified the signature of {}", sha);
+                Ok(())
+            }
+            Err(e) => match e.downcast::<HoganError>() {
+                Ok(HoganError::GitError { msg }) => Err(HoganError::UntrustedCommit {
+                    sha: sha.to_owned(),
+                    msg,
+                }
+                .into()),
+                Ok(e) => Err(e.into()),
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
+
+// git is run in the repository, so relative paths would no longer point at the keys
+fn canonical(path: &Path) -> Result<String> {
+    let path = fs::canonicalize(path)
+        .with_context(|| format!("Unable to find the allowed signers at {:?}", path))?;
+    Ok(path.to_str().unwrap().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn run(dir: &Path, program: &str, args: &[&str]) -> String {
+        let output = Command::new(program)
+            .current_dir(dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    fn ssh_key(dir: &Path, name: &str) -> PathBuf {
+        run(
+            dir,
+            "ssh-keygen",
+            &["-q", "-t", "ed25519", "-N", "", "-C", name, "-f", name],
+        );
+        dir.join(name)
+    }
+
+    fn commit(repo: &Path, signing_key: Option<&Path>) -> String {
+        let mut args = vec![
+            "-c".to_owned(),
+            "user.name=hogan".to_owned(),
+            "-c".to_owned(),
+            "user.email=hogan@example.com".to_owned(),
+            "-c".to_owned(),
+            "gpg.format=ssh".to_owned(),
+        ];
+        if let Some(key) = signing_key {
+            args.push("-c".to_owned());
+            args.push(format!("user.signingkey={}", key.display()));
+        }
+        args.extend(["commit", "--allow-empty", "-m", "Update configs"].map(String::from));
+        args.push(
+            if signing_key.is_some() {
+                "-S"
+            } else {
+                "--no-gpg-sign"
+            }
+            .to_owned(),
+        );
+        run(
+            repo,
+            "git",
+            &args.iter().map(String::as_str).collect::<Vec<_>>(),
+        );
+        run(repo, "git", &["rev-parse", "HEAD"])
+    }
+
+    #[test]
+    fn test_verify_ssh_signatures() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        fs::create_dir(&repo).unwrap();
+        run(&repo, "git", &["init", "-q"]);
+
+        let allowed_key = ssh_key(dir.path(), "allowed");
+        let other_key = ssh_key(dir.path(), "other");
+        let allowed_signers = dir.path().join("allowed_signers");
+        let public_key = fs::read_to_string(allowed_key.with_extension("pub")).unwrap();
+        fs::write(
+            &allowed_signers,
+            format!("hogan@example.com {}", public_key),
+        )
+        .unwrap();
+
+        let signed = commit(&repo, Some(&allowed_key));
+        let untrusted = commit(&repo, Some(&other_key));
+        let unsigned = commit(&repo, None);
+
+        let signers = AllowedSigners {
+            ssh: Some(allowed_signers),
+            gpg: None,
+        };
+        let is_untrusted = |sha: &str, signers: &AllowedSigners| {
+            matches!(
+                signers
+                    .verify_commit(&NativeGit::default(), &repo, sha)
+                    .unwrap_err()
+                    .downcast_ref(),
+                Some(HoganError::UntrustedCommit { .. })
+            )
+        };
+        assert!(signers
+            .verify_commit(&NativeGit::default(), &repo, &signed)
+            .is_ok());
+        assert!(is_untrusted(&untrusted, &signers));
+        assert!(is_untrusted(&unsigned, &signers));
+        // Without an allowed signers file no SSH signature is trusted
+        assert!(is_untrusted(&signed, &AllowedSigners::default()));
+    }
+}
@@ -0,0 +1,397 @@
+use crate::auth::REDACTED;
+use crate::error::HoganError;
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use git2::cert::Cert;
+use git2::{CertificateCheckStatus, Cred};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// The default private keys, in the order they are looked for
+const DEFAULT_KEYS: [&str; 3] = ["id_rsa", "id_ed25519", "id_ecdsa"];
+
+/// Whether host keys are checked against known_hosts, and how hosts which are not in it are
+/// treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HostKeyChecking {
+    /// Leave host keys unchecked by hogan, and to the SSH configuration for native git
+    #[default]
+    Off,
+    /// Refuse to connect to unknown hosts
+    Strict,
+    /// Add the keys of unknown hosts to known_hosts
+    AcceptNew,
+}
+
+impl FromStr for HostKeyChecking {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(HostKeyChecking::Off),
+            "strict" => Ok(HostKeyChecking::Strict),
+            "accept-new" => Ok(HostKeyChecking::AcceptNew),
+            _ => Err(HoganError::InvalidConfiguration {
+                param: "host-key-checking".to_string(),
+                msg: format!("{} is not one of off, strict or accept-new", s),
+            }
+            .into()),
+        }
+    }
+}
+
+/// How to authenticate with and verify git hosts over SSH
+#[derive(Clone, Default)]
+pub struct SshOptions {
+    /// Private key to authenticate with
+    pub key: PathBuf,
+    pub passphrase: Option<String>,
+    /// Try the keys of a running ssh-agent before the private key
+    pub agent: bool,
+    /// Host keys are not checked without a known_hosts file, or when checking is off
+    pub known_hosts: Option<PathBuf>,
+    pub host_key_checking: HostKeyChecking,
+}
+
+impl SshOptions {
+    /// The private key to use. When a default key such as `~/.ssh/id_rsa` does not exist, the
+    /// first of the other default keys next to it is used instead
+    pub fn key_path(&self) -> PathBuf {
+        let is_default = self
+            .key
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| DEFAULT_KEYS.contains(&name))
+            .unwrap_or(false);
+        if self.key.exists() || !is_default {
+            return self.key.clone();
+        }
+
+        let dir = self.key.parent().unwrap_or_else(|| Path::new(""));
+        DEFAULT_KEYS
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|key| key.exists())
+            .unwrap_or_else(|| self.key.clone())
+    }
+
+    /// The credential to offer on an attempt to authenticate, trying the ssh-agent before the
+    /// private key. There is none once they have all been offered
+    pub fn credential(&self, username: &str, attempt: usize) -> Option<Result<Cred, git2::Error>> {
+        match (attempt, self.agent) {
+            (0, true) => {
+                debug!("Using ssh-agent auth");
+                Some(Cred::ssh_key_from_agent(username))
+            }
+            (0, false) | (1, true) => {
+                let key = self.key_path();
+                debug!("Using SSH auth with {:?}", key);
+                Some(Cred::ssh_key(
+                    username,
+                    None,
+                    &key,
+                    self.passphrase.as_deref(),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks SSH host keys against known_hosts, leaving TLS certificates to libgit2. The port is
+    /// used to find hosts which are not on port 22
+    pub fn check_certificate(
+        &self,
+        cert: &Cert,
+        hostname: &str,
+        port: Option<u16>,
+    ) -> Result<CertificateCheckStatus, git2::Error> {
+        let (known_hosts, hostkey) = match (&self.known_hosts, cert.as_hostkey()) {
+            (Some(known_hosts), Some(hostkey))
+                if self.host_key_checking != HostKeyChecking::Off =>
+            {
+                (known_hosts, hostkey)
+            }
+            _ => return Ok(CertificateCheckStatus::CertificatePassthrough),
+        };
+        let (key, key_type) = match (hostkey.hostkey(), hostkey.hostkey_type()) {
+            (Some(key), Some(key_type)) => (key, key_type),
+            _ => return Err(git2::Error::from_str("The SSH host key is unavailable")),
+        };
+
+        let host = match port {
+            Some(port) if port != 22 => format!("[{}]:{}", hostname, port),
+            _ => hostname.to_owned(),
+        };
+        check_host_key(
+            known_hosts,
+            self.host_key_checking,
+            &host,
+            key_type.name(),
+            key,
+        )
+        .map(|_| CertificateCheckStatus::CertificateOk)
+        .map_err(|e| git2::Error::from_str(&format!("{:#}", e)))
+    }
+}
+
+impl fmt::Debug for SshOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SshOptions")
+            .field("key", &self.key)
+            .field("passphrase", &self.passphrase.as_ref().map(|_| REDACTED))
+            .field("agent", &self.agent)
+            .field("known_hosts", &self.known_hosts)
+            .field("host_key_checking", &self.host_key_checking)
+            .finish()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum KnownHost {
+    Match,
+    Mismatch,
+    Unknown,
+}
+
+/// Checks a host key against a known_hosts file, adding it when the host is unknown and new hosts
+/// are accepted. Keys which don't match are refused even when checking is off
+pub fn check_host_key(
+    known_hosts: &Path,
+    checking: HostKeyChecking,
+    host: &str,
+    key_type: &str,
+    key: &[u8],
+) -> Result<()> {
+    let contents = match fs::read_to_string(known_hosts) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Unable to read known hosts {:?}", known_hosts))
+        }
+    };
+
+    match find_known_host(&contents, host, key_type, key) {
+        KnownHost::Match => Ok(()),
+        KnownHost::Mismatch => Err(HoganError::GitError {
+            msg: format!(
+                "The {} host key of {} does not match the one in {:?}. It may have been changed, or someone may be intercepting the connection",
+                key_type, host, known_hosts
+            ),
+        }
+        .into()),
+        KnownHost::Unknown if checking == HostKeyChecking::Strict => Err(HoganError::GitError {
+            msg: format!(
+                "{} is not in {:?}. Add its host key, for example with ssh-keyscan",
+                host, known_hosts
+            ),
+        }
+        .into()),
+        KnownHost::Unknown if checking == HostKeyChecking::Off => Ok(()),
+        KnownHost::Unknown => {
+            info!("Adding the {} host key of {} to {:?}", key_type, host, known_hosts);
+            if let Some(dir) = known_hosts.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(known_hosts)
+                .with_context(|| format!("Unable to open known hosts {:?}", known_hosts))?;
+            if !contents.is_empty() && !contents.ends_with('\n') {
+                writeln!(file)?;
+            }
+            writeln!(file, "{} {} {}", host, key_type, BASE64.encode(key))
+                .with_context(|| format!("Unable to write known hosts {:?}", known_hosts))
+        }
+    }
+}
+
+fn find_known_host(contents: &str, host: &str, key_type: &str, key: &[u8]) -> KnownHost {
+    let mut known_host = KnownHost::Unknown;
+    for line in contents.lines().map(|line| line.trim()) {
+        // Certificate authorities are not supported, and revoked keys are never matched
+        if line.is_empty() || line.starts_with('#') || line.starts_with('@') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        if let (Some(hosts), Some(line_type), Some(line_key)) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            if line_type != key_type || !hosts_match(hosts, host) {
+                continue;
+            }
+            match BASE64.decode(line_key) {
+                Ok(line_key) if line_key == key => return KnownHost::Match,
+                _ => known_host = KnownHost::Mismatch,
+            }
+        }
+    }
+    known_host
+}
+
+// Matches a host against the hosts of a known_hosts line, which are either hashed or a comma
+// separated list of patterns
+fn hosts_match(hosts: &str, host: &str) -> bool {
+    if let Some(hashed) = hosts.strip_prefix("|1|") {
+        let (salt, hash) = match hashed.split_once('|') {
+            Some((salt, hash)) => (BASE64.decode(salt), BASE64.decode(hash)),
+            None => return false,
+        };
+        return match (salt, hash) {
+            (Ok(salt), Ok(hash)) => Hmac::<Sha1>::new_from_slice(&salt)
+                .map(|mut mac| {
+                    mac.update(host.as_bytes());
+                    mac.verify_slice(&hash).is_ok()
+                })
+                .unwrap_or(false),
+            _ => false,
+        };
+    }
+
+    let mut matched = false;
+    for pattern in hosts.split(',') {
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob_match(negated, host) => return false,
+            Some(_) => {}
+            None => matched |= glob_match(pattern, host),
+        }
+    }
+    matched
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.chars().next() {
+        None => text.is_empty(),
+        Some('*') => (0..=text.len())
+            .filter(|i| text.is_char_boundary(*i))
+            .any(|i| glob_match(&pattern[1..], &text[i..])),
+        Some(c) => match text.chars().next() {
+            Some(t) if c == '?' || c.eq_ignore_ascii_case(&t) => {
+                glob_match(&pattern[c.len_utf8()..], &text[t.len_utf8()..])
+            }
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"host key";
+
+    fn known_hosts(line: &str) -> String {
+        format!("# comment\n{}\n", line)
+    }
+
+    #[test]
+    fn test_find_known_host() {
+        let encoded = BASE64.encode(KEY);
+        let contents = known_hosts(&format!("github.com,*.example.com ssh-ed25519 {}", encoded));
+
+        assert_eq!(
+            find_known_host(&contents, "github.com", "ssh-ed25519", KEY),
+            KnownHost::Match
+        );
+        assert_eq!(
+            find_known_host(&contents, "git.example.com", "ssh-ed25519", KEY),
+            KnownHost::Match
+        );
+        assert_eq!(
+            find_known_host(&contents, "github.com", "ssh-ed25519", b"other key"),
+            KnownHost::Mismatch
+        );
+        assert_eq!(
+            find_known_host(&contents, "github.com", "ssh-rsa", KEY),
+            KnownHost::Unknown
+        );
+        assert_eq!(
+            find_known_host(&contents, "gitlab.com", "ssh-ed25519", KEY),
+            KnownHost::Unknown
+        );
+        assert_eq!(
+            find_known_host(
+                &known_hosts(&format!("*,!github.com ssh-ed25519 {}", encoded)),
+                "github.com",
+                "ssh-ed25519",
+                KEY
+            ),
+            KnownHost::Unknown
+        );
+    }
+
+    #[test]
+    fn test_find_hashed_known_host() {
+        let salt = b"0123456789abcdefghij";
+        let mut mac = Hmac::<Sha1>::new_from_slice(salt).unwrap();
+        mac.update(b"[git.example.com]:2222");
+        let contents = known_hosts(&format!(
+            "|1|{}|{} ssh-rsa {}",
+            BASE64.encode(salt),
+            BASE64.encode(mac.finalize().into_bytes()),
+            BASE64.encode(KEY)
+        ));
+
+        assert_eq!(
+            find_known_host(&contents, "[git.example.com]:2222", "ssh-rsa", KEY),
+            KnownHost::Match
+        );
+        assert_eq!(
+            find_known_host(&contents, "git.example.com", "ssh-rsa", KEY),
+            KnownHost::Unknown
+        );
+    }
+
+    #[test]
+    fn test_check_host_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("known_hosts");
+
+        check_host_key(&path, HostKeyChecking::Off, "github.com", "ssh-rsa", KEY).unwrap();
+        assert!(
+            check_host_key(&path, HostKeyChecking::Strict, "github.com", "ssh-rsa", KEY).is_err()
+        );
+        assert!(!path.exists());
+
+        check_host_key(
+            &path,
+            HostKeyChecking::AcceptNew,
+            "github.com",
+            "ssh-rsa",
+            KEY,
+        )
+        .unwrap();
+        check_host_key(&path, HostKeyChecking::Strict, "github.com", "ssh-rsa", KEY).unwrap();
+        assert!(check_host_key(
+            &path,
+            HostKeyChecking::AcceptNew,
+            "github.com",
+            "ssh-rsa",
+            b"changed"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_key_path_falls_back_to_other_default_keys() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let ssh = SshOptions {
+            key: temp_dir.path().join("id_rsa"),
+            ..Default::default()
+        };
+        assert_eq!(ssh.key_path(), temp_dir.path().join("id_rsa"));
+
+        fs::write(temp_dir.path().join("id_ed25519"), "").unwrap();
+        assert_eq!(ssh.key_path(), temp_dir.path().join("id_ed25519"));
+
+        fs::write(temp_dir.path().join("id_rsa"), "").unwrap();
+        assert_eq!(ssh.key_path(), temp_dir.path().join("id_rsa"));
+    }
+}
@@ -1,35 +1,47 @@
 use crate::config::Environment;
 use crate::error::HoganError;
 use crate::find_file_paths;
+use crate::transform::{EmittedFiles, FileHelper};
 use anyhow::{Context, Result};
-use handlebars::Handlebars;
+use handlebars::template::{Parameter, Template as HbTemplate, TemplateElement};
+use handlebars::{
+    BlockParams, Context as HbContext, Handlebars, Output, Path as HbPath, RenderContext,
+    RenderError, Renderable, TemplateError,
+};
 use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
 use zip::write::{FileOptions, ZipWriter};
 use zip::CompressionMethod::Stored;
 
-use std::clone::Clone;
+use std::collections::HashMap;
 use std::fs;
 use std::io::{Cursor, Write};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
 pub struct TemplateDir {
     directory: PathBuf,
+    // Also skip the files ignored by the project's .gitignore, not just the .hoganignore
+    gitignore: bool,
 }
 
 impl TemplateDir {
-    pub fn new(path: PathBuf) -> Result<TemplateDir> {
+    pub fn new(path: PathBuf, gitignore: bool) -> Result<TemplateDir> {
         if !path.is_dir() {
             Err(HoganError::UnknownError {
                 msg: "Unable to find the template path".to_string(),
             })
             .with_context(|| format!("The path {:?} needs to exist and be a directory", path))
         } else {
-            Ok(TemplateDir { directory: path })
+            Ok(TemplateDir {
+                directory: path,
+                gitignore,
+            })
         }
     }
 
     pub fn find(&self, filter: Regex) -> Vec<Template> {
-        find_file_paths(&self.directory, filter)
+        find_file_paths(&self.directory, filter, self.gitignore)
             .filter_map(|path| Template::from_path_buf(path).ok())
             .collect()
     }
@@ -38,57 +50,162 @@ impl TemplateDir {
 pub struct Template {
     pub path: PathBuf,
     pub contents: String,
+    // Parsed once so rendering against many environments skips reparsing the source
+    compiled: Option<CompiledTemplate>,
 }
 
 impl Template {
+    pub fn new(path: PathBuf, contents: String) -> Template {
+        let compiled = CompiledTemplate::compile(&contents).ok();
+        Template {
+            path,
+            contents,
+            compiled,
+        }
+    }
+
     fn from_path_buf(path: PathBuf) -> Result<Template> {
-        Ok(Template {
-            path: path.clone(),
-            contents: fs::read_to_string(path)?,
-        })
+        let contents = fs::read_to_string(&path)?;
+        Ok(Template::new(path, contents))
     }
 }
 
 impl Template {
-    pub fn render(&self, handlebars: &Handlebars, environment: &Environment) -> Result<Rendered> {
+    /// Renders the template for an environment, followed by any files it emits with `hogan-file`
+    pub fn render(
+        &self,
+        handlebars: &Handlebars,
+        environment: &Environment,
+        sha: Option<&str>,
+    ) -> Result<Vec<Rendered>> {
+        let metadata = Metadata {
+            template_path: Some(&self.path),
+            ..Metadata::new(environment, sha)
+        };
         let mut buf = Cursor::new(Vec::new());
-        handlebars
-            .render_template_to_write(&self.contents, &environment.config_data, &mut buf)
-            .with_context(|| {
-                format!(
-                    "Error when rendering file:{:?} env:{}",
-                    self.path.file_name(),
-                    environment.environment,
-                )
-            })?;
-
-        Ok(Rendered {
-            path: self.path.with_file_name(
-                self.path
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .replace("template", &environment.environment),
-            ),
-            contents: buf.into_inner(),
+        let (path, files) = match self.compiled {
+            Some(ref compiled) => {
+                compiled.render(handlebars, &environment.config_data, &metadata, &mut buf)
+            }
+            // Rendering the source reports the parse error in the same way as other render errors
+            None => handlebars
+                .render_template_to_write(&self.contents, &environment.config_data, &mut buf)
+                .map(|_| Vec::new()),
+        }
+        .and_then(|files| {
+            let path = self.output_path(handlebars, environment, &metadata)?;
+            Ok((path, files))
         })
+        .with_context(|| {
+            format!(
+                "Error when rendering file:{:?} env:{}",
+                self.path.file_name(),
+                environment.environment,
+            )
+        })?;
+
+        let contents = buf.into_inner();
+        let mut rendered = Vec::with_capacity(files.len() + 1);
+        // A template that only emits files would otherwise leave a blank file behind
+        if files.is_empty() || !String::from_utf8_lossy(&contents).trim().is_empty() {
+            rendered.push(Rendered {
+                path: path.clone(),
+                contents,
+            });
+        }
+        rendered.extend(files.into_iter().map(|file| Rendered {
+            path: path.with_file_name(file.path),
+            contents: file.contents,
+        }));
+
+        Ok(rendered)
+    }
+
+    // The file name has `template` replaced with the environment name, and any path components
+    // holding handlebars expressions are rendered against the environment
+    fn output_path(
+        &self,
+        handlebars: &Handlebars,
+        environment: &Environment,
+        metadata: &Metadata,
+    ) -> Result<PathBuf, RenderError> {
+        let file_name = self.path.file_name().unwrap().to_string_lossy();
+        let file_name =
+            replace_outside_expressions(&file_name, "template", &environment.environment);
+        let directory = self.path.parent().unwrap_or_else(|| Path::new(""));
+        if !self.path.to_string_lossy().contains("{{") {
+            return Ok(directory.join(file_name));
+        }
+
+        let mut path = PathBuf::new();
+        for component in directory
+            .iter()
+            .map(|c| c.to_string_lossy())
+            .chain(std::iter::once(file_name.into()))
+        {
+            if !component.contains("{{") {
+                path.push(component.as_ref());
+                continue;
+            }
+
+            let mut rendered = Vec::new();
+            let mut compiled = CompiledTemplate::compile(&component)
+                .map_err(|e| RenderError::new(format!("Invalid path {:?}: {}", component, e)))?;
+            unescape_expressions(&mut compiled.template);
+            compiled.render(
+                handlebars,
+                &environment.config_data,
+                metadata,
+                &mut rendered,
+            )?;
+            let rendered = String::from_utf8(rendered)?;
+            if rendered.is_empty()
+                || !Path::new(&rendered)
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(RenderError::new(format!(
+                    "Path {:?} rendered to the invalid name {:?}",
+                    component, rendered
+                )));
+            }
+            path.push(rendered);
+        }
+        Ok(path)
+    }
+
+    // The deepest directory of the template which does not depend on the environment
+    fn base_directory(&self) -> PathBuf {
+        self.path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .iter()
+            .take_while(|c| !c.to_string_lossy().contains("{{"))
+            .collect()
     }
 
     pub fn render_to_zip(
         &self,
         handlebars: &Handlebars,
         environments: &[Environment],
+        sha: Option<&str>,
     ) -> Result<Vec<u8>> {
         let options = FileOptions::default().compression_method(Stored);
         let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let directory = self.base_directory();
+        let mut outputs = OutputPaths::default();
 
         for environment in environments {
-            let rendered = self.render(handlebars, environment)?;
-            zip.start_file(
-                rendered.path.file_name().unwrap().to_string_lossy(),
-                options,
-            )?;
-            zip.write_all(&rendered.contents)?;
+            for rendered in self.render(handlebars, environment, sha)? {
+                let name = rendered
+                    .path
+                    .strip_prefix(&directory)
+                    .unwrap_or(&rendered.path);
+                // zip writes duplicate entries without complaint
+                outputs.claim(name, &environment.environment)?;
+                zip.start_file(name.to_string_lossy(), options)?;
+                zip.write_all(&rendered.contents)?;
+            }
         }
 
         Ok(zip.finish()?.into_inner())
@@ -100,6 +217,286 @@ pub struct Rendered {
     pub contents: Vec<u8>,
 }
 
+/// The outputs of a transform, so that two renders writing the same path is an error rather than
+/// one silently replacing the other. Files emitted with `hogan-file` have the same path for every
+/// environment unless it includes `{{@hogan.environment}}`
+#[derive(Debug, Default)]
+pub struct OutputPaths {
+    environments: HashMap<PathBuf, String>,
+}
+
+impl OutputPaths {
+    pub fn claim(&mut self, path: &Path, environment: &str) -> Result<()> {
+        match self.environments.get(path) {
+            Some(previous) => {
+                let msg = format!(
+                    "{:?} was already rendered for environment {}. Paths of files emitted with \
+                     hogan-file can include {{{{@hogan.environment}}}} to tell environments apart",
+                    path, previous
+                );
+                Err(HoganError::InvalidTemplate {
+                    msg: msg.clone(),
+                    env: environment.to_owned(),
+                })
+                .context(msg)
+            }
+            None => {
+                self.environments
+                    .insert(path.to_path_buf(), environment.to_owned());
+                Ok(())
+            }
+        }
+    }
+}
+
+fn replace_outside_expressions(source: &str, from: &str, to: &str) -> String {
+    let mut replaced = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        replaced.push_str(&rest[..start].replace(from, to));
+        let end = rest[start..]
+            .find("}}")
+            .map(|end| start + end + 2)
+            .unwrap_or(rest.len());
+        replaced.push_str(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    replaced.push_str(&rest.replace(from, to));
+    replaced
+}
+
+const METADATA_VARIABLE: &str = "hogan";
+// The block param holding the metadata of a render, which no config key can be mistaken for
+const METADATA_PARAM: &str = "@hogan";
+
+/// Hogan metadata that templates can read through the `@hogan` data variable
+#[derive(Debug, Serialize)]
+pub struct Metadata<'a> {
+    pub environment: &'a str,
+    pub environment_type: Option<&'a str>,
+    pub sha: Option<&'a str>,
+    pub template_path: Option<&'a Path>,
+    pub version: &'a str,
+}
+
+impl<'a> Metadata<'a> {
+    pub fn new(environment: &'a Environment, sha: Option<&'a str>) -> Metadata<'a> {
+        Metadata {
+            environment: &environment.environment,
+            environment_type: environment.environment_type.as_deref(),
+            sha,
+            template_path: None,
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
+
+/// A template parsed once up front so it can be rendered many times
+#[derive(Debug)]
+pub struct CompiledTemplate {
+    template: HbTemplate,
+    // The metadata fields the template reads, empty for the whole `@hogan` object
+    metadata_fields: Vec<String>,
+}
+
+impl CompiledTemplate {
+    pub fn compile(source: &str) -> Result<CompiledTemplate, Box<TemplateError>> {
+        let mut template = HbTemplate::compile(source)?;
+        let metadata_fields = bind_metadata(&mut template);
+
+        Ok(CompiledTemplate {
+            template,
+            metadata_fields,
+        })
+    }
+
+    /// Renders the template with the helpers and settings of the registry, returning the files
+    /// emitted with `hogan-file` with paths relative to the template
+    pub fn render<T, W>(
+        &self,
+        handlebars: &Handlebars,
+        data: &T,
+        metadata: &Metadata,
+        writer: W,
+    ) -> Result<Vec<Rendered>, RenderError>
+    where
+        T: Serialize,
+        W: Write,
+    {
+        let metadata = serde_json::to_value(metadata)?;
+        let context = HbContext::wraps(data)?;
+        let mut render_context = RenderContext::new(None);
+        check_metadata(&self.metadata_fields, &metadata)?;
+        // Emitted file paths can read the metadata even when the template does not
+        set_metadata(&mut render_context, metadata.clone())?;
+        let files = EmittedFiles::default();
+        render_context.register_local_helper(
+            "hogan-file",
+            Box::new(FileHelper {
+                files: files.clone(),
+                metadata,
+            }),
+        );
+        let mut output = WriteOutput { writer };
+        self.template
+            .render(handlebars, &context, &mut render_context, &mut output)?;
+        drop(render_context);
+
+        let files = std::mem::take(&mut *files.lock());
+        Ok(files
+            .into_iter()
+            .map(|(path, contents)| Rendered {
+                path: PathBuf::from(path),
+                contents,
+            })
+            .collect())
+    }
+}
+
+// handlebars only resolves the first segment of a data variable, so `@hogan.*` references are
+// rewritten once into paths under a block param, which `set_metadata` fills in for each render.
+// Returns the metadata fields the template reads
+pub(crate) fn bind_metadata(template: &mut HbTemplate) -> Vec<String> {
+    let mut fields = Vec::new();
+    let _ = visit_parameters(template, &mut |parameter| {
+        let field = match metadata_field(parameter) {
+            Some(field) => field.to_owned(),
+            None => return Ok(()),
+        };
+        if let Parameter::Path(HbPath::Local((_, _, raw))) = parameter {
+            let path =
+                HbPath::parse(&raw.replacen(METADATA_PARAM, &format!("[{}]", METADATA_PARAM), 1))?;
+            fields.push(field);
+            *parameter = Parameter::Path(path);
+        }
+        Ok(())
+    });
+    fields
+}
+
+/// Fails on the first field bound by `bind_metadata` which the metadata does not have
+pub(crate) fn check_metadata(fields: &[String], metadata: &Value) -> Result<(), RenderError> {
+    match fields
+        .iter()
+        .find(|field| !field.is_empty() && metadata.get(field.as_str()).is_none())
+    {
+        Some(field) => Err(RenderError::new(format!(
+            "Unknown metadata @{}.{}",
+            METADATA_VARIABLE, field
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Makes the metadata available to the references bound by `bind_metadata`, from the root block
+/// of a render down
+pub(crate) fn set_metadata(
+    render_context: &mut RenderContext,
+    metadata: Value,
+) -> Result<(), RenderError> {
+    let mut params = BlockParams::new();
+    params.add_value(METADATA_PARAM, metadata)?;
+    if let Some(block) = render_context.block_mut() {
+        block.set_block_params(params);
+    }
+    Ok(())
+}
+
+// The metadata field a parameter refers to, empty for the whole `@hogan` object
+fn metadata_field(parameter: &Parameter) -> Option<&str> {
+    match parameter {
+        Parameter::Path(HbPath::Local((_, name, raw))) if name == METADATA_VARIABLE => raw
+            .split_once(&format!("@{}", METADATA_VARIABLE))
+            .map(|(_, field)| field.trim_start_matches(&['.', '/'][..])),
+        _ => None,
+    }
+}
+
+fn visit_parameters<F>(template: &mut HbTemplate, visit: &mut F) -> Result<(), RenderError>
+where
+    F: FnMut(&mut Parameter) -> Result<(), RenderError>,
+{
+    for element in template.elements.iter_mut() {
+        visit_element(element, visit)?;
+    }
+    Ok(())
+}
+
+fn visit_element<F>(element: &mut TemplateElement, visit: &mut F) -> Result<(), RenderError>
+where
+    F: FnMut(&mut Parameter) -> Result<(), RenderError>,
+{
+    let (name, params, hash, templates) = match element {
+        TemplateElement::Expression(ref mut ht)
+        | TemplateElement::HtmlExpression(ref mut ht)
+        | TemplateElement::HelperBlock(ref mut ht) => (
+            &mut ht.name,
+            &mut ht.params,
+            &mut ht.hash,
+            vec![ht.template.as_mut(), ht.inverse.as_mut()],
+        ),
+        TemplateElement::DecoratorExpression(ref mut dt)
+        | TemplateElement::DecoratorBlock(ref mut dt)
+        | TemplateElement::PartialExpression(ref mut dt)
+        | TemplateElement::PartialBlock(ref mut dt) => (
+            &mut dt.name,
+            &mut dt.params,
+            &mut dt.hash,
+            vec![dt.template.as_mut()],
+        ),
+        TemplateElement::RawString(_) | TemplateElement::Comment(_) => return Ok(()),
+    };
+
+    for parameter in std::iter::once(name)
+        .chain(params.iter_mut())
+        .chain(hash.values_mut())
+    {
+        match parameter {
+            Parameter::Subexpression(ref mut subexpression) => {
+                visit_element(&mut subexpression.element, visit)?
+            }
+            parameter => visit(parameter)?,
+        }
+    }
+    for template in templates.into_iter().flatten() {
+        visit_parameters(template, visit)?;
+    }
+    Ok(())
+}
+
+// Rendered names are not HTML, so expressions are written as they would be triple-stashed
+pub(crate) fn unescape_expressions(template: &mut HbTemplate) {
+    for element in template.elements.iter_mut() {
+        match element {
+            TemplateElement::Expression(_) => {
+                let empty = TemplateElement::RawString(String::new());
+                if let TemplateElement::Expression(ht) = std::mem::replace(element, empty) {
+                    *element = TemplateElement::HtmlExpression(ht);
+                }
+            }
+            TemplateElement::HelperBlock(ref mut ht) => {
+                for template in [ht.template.as_mut(), ht.inverse.as_mut()]
+                    .into_iter()
+                    .flatten()
+                {
+                    unescape_expressions(template);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+struct WriteOutput<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> Output for WriteOutput<W> {
+    fn write(&mut self, seg: &str) -> std::io::Result<()> {
+        self.writer.write_all(seg.as_bytes())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -108,7 +505,7 @@ mod tests {
     #[test]
     fn test_find_all_templates() {
         let template_dir =
-            TemplateDir::new(PathBuf::from("tests/fixtures/projects/templates")).unwrap();
+            TemplateDir::new(PathBuf::from("tests/fixtures/projects/templates"), false).unwrap();
         let templates = template_dir.find(
             RegexBuilder::new("^[^.]*(\\w+\\.)*template([-.].+)?\\.(config|ya?ml|properties)$")
                 .case_insensitive(true)
@@ -117,4 +514,214 @@ mod tests {
         );
         assert_eq!(templates.len(), 6)
     }
+
+    #[test]
+    fn test_find_templates_skips_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in &[
+            "app.template.yaml",
+            "node_modules/lib/template.yaml",
+            "vendor/template.yaml",
+            ".git/template.yaml",
+        ] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::write(dir.path().join(crate::IGNORE_FILE), "node_modules/\n").unwrap();
+        fs::write(dir.path().join(".gitignore"), "vendor/\n").unwrap();
+        let filter = Regex::new("template\\.yaml$").unwrap();
+
+        let found = |gitignore| {
+            let mut paths = TemplateDir::new(dir.path().to_path_buf(), gitignore)
+                .unwrap()
+                .find(filter.clone())
+                .into_iter()
+                .map(|t| t.path.strip_prefix(dir.path()).unwrap().to_path_buf())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+        assert_eq!(
+            found(false),
+            vec![
+                PathBuf::from("app.template.yaml"),
+                PathBuf::from("vendor/template.yaml")
+            ]
+        );
+        // .gitignore files only apply inside a git repository
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        assert_eq!(found(true), vec![PathBuf::from("app.template.yaml")]);
+    }
+
+    #[test]
+    fn test_compiled_render_matches_source_render() {
+        let handlebars = crate::transform::handlebars(true);
+        let environment = Environment {
+            environment: "TEST".to_string(),
+            environment_type: None,
+            config_data: serde_json::json!({ "Name": "hogan", "Ports": [80, 443] }),
+        };
+        let source = "{{Name}}:{{#each Ports}}{{this}},{{/each}}";
+        let template = Template::new(PathBuf::from("Web.template.config"), source.to_string());
+
+        let rendered = template
+            .render(&handlebars, &environment, None)
+            .unwrap()
+            .remove(0);
+        let expected = handlebars
+            .render_template(source, &environment.config_data)
+            .unwrap();
+        assert_eq!(String::from_utf8(rendered.contents).unwrap(), expected);
+        assert_eq!(rendered.path, PathBuf::from("Web.TEST.config"));
+    }
+
+    #[test]
+    fn test_invalid_template_fails_to_render() {
+        let handlebars = crate::transform::handlebars(true);
+        let environment = Environment {
+            environment: "TEST".to_string(),
+            environment_type: None,
+            config_data: serde_json::json!({}),
+        };
+        let template = Template::new(PathBuf::from("Web.template.config"), "{{#if}".to_string());
+
+        assert!(template.render(&handlebars, &environment, None).is_err());
+    }
+
+    #[test]
+    fn test_render_metadata() {
+        let handlebars = crate::transform::handlebars(true);
+        let environment = Environment {
+            environment: "TEST".to_string(),
+            environment_type: Some("test".to_string()),
+            config_data: serde_json::json!({ "Ports": [80], "hogan": "config" }),
+        };
+        let source = "{{@hogan.environment}}/{{@hogan.environment_type}}/{{@hogan.sha}}/\
+            {{@hogan.template_path}}/{{@hogan.version}}\
+            {{#each Ports}}|{{this}}:{{@hogan.environment}}{{/each}}\
+            {{#if (eq @hogan.environment \"TEST\")}}|test{{/if}}|{{hogan}}";
+        let template = Template::new(PathBuf::from("Web.template.config"), source.to_string());
+
+        let rendered = template
+            .render(&handlebars, &environment, Some("abc123"))
+            .unwrap()
+            .remove(0);
+        assert_eq!(
+            String::from_utf8(rendered.contents).unwrap(),
+            format!(
+                "TEST/test/abc123/Web.template.config/{}|80:TEST|test|config",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+
+    #[test]
+    fn test_unknown_metadata_fails_to_render() {
+        let handlebars = crate::transform::handlebars(true);
+        let environment = Environment {
+            environment: "TEST".to_string(),
+            environment_type: None,
+            config_data: serde_json::json!({}),
+        };
+        let template = Template::new(
+            PathBuf::from("Web.template.config"),
+            "{{@hogan.unknown}}".to_string(),
+        );
+
+        assert!(template.render(&handlebars, &environment, None).is_err());
+    }
+
+    #[test]
+    fn test_render_emitted_files() {
+        let handlebars = crate::transform::handlebars(true);
+        let environment = Environment {
+            environment: "TEST".to_string(),
+            environment_type: None,
+            config_data: serde_json::json!({ "Services": [{ "name": "auth" }, { "name": "search" }] }),
+        };
+        let source = "{{#each Services}}\n{{#hogan-file \"conf.d/{{name}}.conf\"}}{{name}}{{/hogan-file}}\n{{/each}}";
+        let template = Template::new(PathBuf::from("nginx/vhost.template"), source.to_string());
+
+        let rendered = template.render(&handlebars, &environment, None).unwrap();
+        assert_eq!(
+            rendered.iter().map(|r| r.path.clone()).collect::<Vec<_>>(),
+            vec![
+                PathBuf::from("nginx/conf.d/auth.conf"),
+                PathBuf::from("nginx/conf.d/search.conf")
+            ]
+        );
+        assert_eq!(rendered[1].contents, b"search");
+    }
+
+    #[test]
+    fn test_render_to_zip_rejects_shared_emitted_paths() {
+        let handlebars = crate::transform::handlebars(true);
+        let environments = ["TEST", "TEST2"]
+            .iter()
+            .map(|name| Environment {
+                environment: name.to_string(),
+                environment_type: None,
+                config_data: serde_json::json!({}),
+            })
+            .collect::<Vec<_>>();
+        let zip = |path: &str| {
+            Template::new(
+                PathBuf::from("nginx/vhost.template"),
+                format!("{{{{#hogan-file \"{}\"}}}}x{{{{/hogan-file}}}}", path),
+            )
+            .render_to_zip(&handlebars, &environments, None)
+        };
+
+        assert!(zip("app.conf").is_err());
+        assert!(zip("{{@hogan.environment}}.conf").is_ok());
+    }
+
+    #[test]
+    fn test_render_templated_path() {
+        let handlebars = crate::transform::handlebars(true);
+        let environment = Environment {
+            environment: "TEST".to_string(),
+            environment_type: None,
+            config_data: serde_json::json!({ "Region": { "Key": "us-east" }, "Tier": "web-template" }),
+        };
+        let template = Template::new(
+            PathBuf::from("templates/{{Region.Key}}/app-{{Tier}}.template.yaml"),
+            "app".to_string(),
+        );
+
+        let rendered = template.render(&handlebars, &environment, None).unwrap();
+        assert_eq!(
+            rendered[0].path,
+            PathBuf::from("templates/us-east/app-web-template.TEST.yaml")
+        );
+
+        // Names are not HTML escaped
+        let environment = Environment {
+            config_data: serde_json::json!({ "Tier": "web&api", "Name": "<app>" }),
+            ..environment
+        };
+        let template = Template::new(
+            PathBuf::from("{{Tier}}/{{Name}}.template.yaml"),
+            "app".to_string(),
+        );
+        let rendered = template.render(&handlebars, &environment, None).unwrap();
+        assert_eq!(rendered[0].path, PathBuf::from("web&api/<app>.TEST.yaml"));
+    }
+
+    #[test]
+    fn test_render_templated_path_outside_directory() {
+        let handlebars = crate::transform::handlebars(true);
+        let environment = Environment {
+            environment: "TEST".to_string(),
+            environment_type: None,
+            config_data: serde_json::json!({ "Region": ".." }),
+        };
+        let template = Template::new(
+            PathBuf::from("templates/{{Region}}/app.template.yaml"),
+            "app".to_string(),
+        );
+
+        assert!(template.render(&handlebars, &environment, None).is_err());
+    }
 }
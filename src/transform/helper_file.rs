@@ -0,0 +1,152 @@
+use crate::template::{bind_metadata, check_metadata, unescape_expressions};
+use handlebars::*;
+use parking_lot::Mutex;
+use serde_json::value::Value as Json;
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+// Files emitted during a single render, as paths relative to the template with their contents
+pub type EmittedFiles = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+#[derive(Clone)]
+pub struct FileHelper {
+    pub files: EmittedFiles,
+    pub metadata: Json,
+}
+
+impl HelperDef for FileHelper {
+    fn call<'reg: 'rc, 'rc, 'ctx>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars,
+        ctx: &'ctx Context,
+        rc: &mut RenderContext<'reg, 'ctx>,
+        _: &mut dyn Output,
+    ) -> HelperResult {
+        let path = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("Path param not found for helper \"hogan-file\""))?;
+
+        // The path is itself a template, rendered in the current scope with its block params
+        let mut path_template = template::Template::compile(path)?;
+        let fields = bind_metadata(&mut path_template);
+        check_metadata(&fields, &self.metadata)?;
+        unescape_expressions(&mut path_template);
+        let mut path = StringOutput::new();
+        path_template.render(r, ctx, &mut rc.clone(), &mut path)?;
+        let path = path.into_string()?;
+        // An empty path, or one of only `.`, names no file
+        let components = Path::new(&path).components().collect::<Vec<_>>();
+        if !components.iter().any(|c| matches!(c, Component::Normal(_)))
+            || !components
+                .iter()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(RenderError::new(format!(
+                "Path {:?} for helper \"hogan-file\" must name a file relative to the template",
+                path
+            )));
+        }
+
+        let mut contents = StringOutput::new();
+        if let Some(t) = h.template() {
+            t.render(r, ctx, rc, &mut contents)?;
+        }
+
+        let mut files = self.files.lock();
+        if files.iter().any(|(p, _)| *p == path) {
+            return Err(RenderError::new(format!(
+                "Path {:?} was emitted more than once by helper \"hogan-file\"",
+                path
+            )));
+        }
+        files.push((path, contents.into_string()?.into_bytes()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn render(template: &str) -> Result<(String, EmittedFiles), RenderError> {
+        let handlebars = Handlebars::new();
+        let files = EmittedFiles::default();
+        let t = template::Template::compile(template).unwrap();
+        let ctx = Context::wraps(json!({
+            "Region": "us&eu",
+            "Services": [{ "name": "auth", "port": 80 }, { "name": "search", "port": 81 }]
+        }))?;
+        let metadata = json!({ "environment": "TEST" });
+        let mut rc = RenderContext::new(None);
+        crate::template::set_metadata(&mut rc, metadata.clone())?;
+        rc.register_local_helper(
+            "hogan-file",
+            Box::new(FileHelper {
+                files: files.clone(),
+                metadata,
+            }),
+        );
+        let mut out = StringOutput::new();
+        t.render(&handlebars, &ctx, &mut rc, &mut out)?;
+
+        Ok((out.into_string()?, files))
+    }
+
+    #[test]
+    fn test_file() {
+        let (out, files) = render(
+            "main{{#each Services}}{{#hogan-file \"{{@hogan.environment}}/{{name}}.conf\"}}listen {{port}};{{/hogan-file}}{{/each}}",
+        )
+        .unwrap();
+
+        assert_eq!(out, "main");
+        assert_eq!(
+            *files.lock(),
+            vec![
+                ("TEST/auth.conf".to_string(), b"listen 80;".to_vec()),
+                ("TEST/search.conf".to_string(), b"listen 81;".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_path_scope() {
+        let (_, files) = render(
+            "{{#each Services as |service|}}{{#hogan-file \"{{../Region}}/{{@index}}-{{service.name}}.conf\"}}x{{/hogan-file}}{{/each}}",
+        )
+        .unwrap();
+
+        // Values are not HTML escaped in file names
+        assert_eq!(
+            files
+                .lock()
+                .iter()
+                .map(|(path, _)| path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["us&eu/0-auth.conf", "us&eu/1-search.conf"]
+        );
+    }
+
+    #[test]
+    fn test_file_rejects_paths_outside_the_template() {
+        assert!(render("{{#hogan-file \"../escape.conf\"}}x{{/hogan-file}}").is_err());
+        assert!(render("{{#hogan-file \"/etc/escape.conf\"}}x{{/hogan-file}}").is_err());
+    }
+
+    #[test]
+    fn test_file_rejects_empty_paths() {
+        assert!(render("{{#hogan-file \"{{Missing}}\"}}x{{/hogan-file}}").is_err());
+        assert!(render("{{#hogan-file \".\"}}x{{/hogan-file}}").is_err());
+    }
+
+    #[test]
+    fn test_file_rejects_duplicate_paths() {
+        assert!(
+            render("{{#each Services}}{{#hogan-file \"same.conf\"}}x{{/hogan-file}}{{/each}}")
+                .is_err()
+        );
+    }
+}
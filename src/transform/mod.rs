@@ -2,6 +2,7 @@ use handlebars::Handlebars;
 
 mod helper_comma_delimited_list;
 mod helper_equal;
+mod helper_file;
 mod helper_lowercase;
 mod helper_or;
 mod helper_url_add_slash;
@@ -11,6 +12,7 @@ mod helper_yaml_string;
 
 use self::helper_comma_delimited_list::CommaDelimitedListHelper;
 use self::helper_equal::EqualHelper;
+pub(crate) use self::helper_file::{EmittedFiles, FileHelper};
 use self::helper_lowercase::LowercaseHelper;
 use self::helper_or::OrHelper;
 use self::helper_url_add_slash::UrlAddSlashHelper;